@@ -0,0 +1,85 @@
+//! Packed-buffer warp against the texture-sampling warp.
+//!
+//! `cargo bench --bench warp_backends` warps a 1920x1080 frame `FRAMES`
+//! times per backend and interpolation and prints the mean host and kernel
+//! times. Kernel times need `TIMESTAMP_QUERY`.
+
+use std::time::Duration;
+
+use pollster::FutureExt;
+use rustwarp::{
+    image::{Image, Pix, PointF, Size},
+    modules::{
+        warp_perspective::{ImageTransform, Interpolation, WarpPerspective, WarpStats},
+        warp_texture::WarpTexture,
+    },
+    setup::WState,
+    types::WMat3x3Affine,
+};
+
+const FRAMES: u32 = 50;
+const WARMUP: u32 = 3;
+
+fn report(backend: &str, interp: Interpolation, stats: &[WarpStats]) {
+    let mean = |f: &dyn Fn(&WarpStats) -> Duration| {
+        stats.iter().map(f).sum::<Duration>().as_secs_f64() * 1e3 / stats.len() as f64
+    };
+    let kernel = match stats.iter().all(|s| s.kernel.is_some()) {
+        true => format!("{:8.3}", mean(&|s| s.kernel.unwrap())),
+        false => format!("{:>8}", "-"),
+    };
+    println!(
+        "{:<8} {:<10} {:8.3} {:8.3} {:8.3} {} {:8.3}",
+        backend,
+        format!("{:?}", interp),
+        mean(&|s| s.upload),
+        mean(&|s| s.dispatch),
+        mean(&|s| s.download),
+        kernel,
+        mean(&|s| s.total()),
+    );
+}
+
+fn main() {
+    let state = match WState::new().block_on() {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("Skipping benchmark: {}", e);
+            return;
+        }
+    };
+    println!(
+        "{} ({:?})",
+        state.adapter_info.name, state.adapter_info.backend
+    );
+
+    let size = Size::new(1920, 1080);
+    let mut src = Image::new(size);
+    for (i, p) in src.data.iter_mut().enumerate() {
+        *p = Pix::new(i as u8, (i / 7) as u8, (i / 13) as u8, 255);
+    }
+    let center = PointF::new(size.x as f32 / 2.0, size.y as f32 / 2.0);
+    let forward = WMat3x3Affine::get_rotation_matrix_2d(center, 15.0, 1.1);
+    let transform = ImageTransform::from_forward(size, size, forward).unwrap();
+    let mut dst = Image::new(size);
+
+    println!(
+        "{:<8} {:<10} {:>8} {:>8} {:>8} {:>8} {:>8}   (ms per frame)",
+        "backend", "interp", "upload", "submit", "readback", "kernel", "total"
+    );
+    for interp in [Interpolation::None, Interpolation::Bilinear] {
+        let mut buffer = WarpPerspective::new(&state, interp).block_on().unwrap();
+        let stats: Vec<_> = (0..WARMUP + FRAMES)
+            .map(|_| buffer.warp(&transform, &src, &mut dst).block_on().unwrap())
+            .skip(WARMUP as usize)
+            .collect();
+        report("buffer", interp, &stats);
+
+        let mut texture = WarpTexture::new(&state, interp).block_on().unwrap();
+        let stats: Vec<_> = (0..WARMUP + FRAMES)
+            .map(|_| texture.warp(&transform, &src, &mut dst).block_on().unwrap())
+            .skip(WARMUP as usize)
+            .collect();
+        report("texture", interp, &stats);
+    }
+}
@@ -0,0 +1,168 @@
+//! `#[derive(WgslStruct)]` for `rustwarp`.
+//!
+//! The derive only reads the field names and types. Alignment comes from the
+//! fields' `WLayout` impls and is checked against their Rust offsets in const
+//! assertions; the WGSL text is put together by `rustwarp::layout`.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+/// Implements `WgslStruct`, `WLayout`, `WTestable` and `WDistribution` for a
+/// `#[repr(C)]` struct with named fields. Fields starting with `_` are
+/// treated as explicit padding: they are left out of the WGSL struct and
+/// sampled as zero.
+///
+/// Compilation fails if a field is misaligned for its WGSL type in a storage
+/// buffer, or also in a uniform buffer with `#[wgsl(uniform)]`.
+#[proc_macro_derive(WgslStruct, attributes(wgsl))]
+pub fn derive_wgsl_struct(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "WgslStruct can not be derived for generic structs",
+        ));
+    }
+    let is_repr_c = input.attrs.iter().any(|attr| {
+        attr.path().is_ident("repr")
+            && attr
+                .parse_nested_meta(|meta| match meta.path.is_ident("C") {
+                    true => Ok(()),
+                    false => Err(meta.error("")),
+                })
+                .is_ok()
+    });
+    if !is_repr_c {
+        return Err(Error::new_spanned(name, "WgslStruct requires #[repr(C)]"));
+    }
+    let mut uniform = false;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("wgsl")) {
+        attr.parse_nested_meta(|meta| match meta.path.is_ident("uniform") {
+            true => {
+                uniform = true;
+                Ok(())
+            }
+            false => Err(meta.error("expected `uniform`")),
+        })?;
+    }
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new_spanned(name, "WgslStruct requires named fields")),
+        },
+        _ => return Err(Error::new_spanned(name, "WgslStruct requires a struct")),
+    };
+
+    let krate = quote!(::rustwarp);
+    let layout = quote!(#krate::types::WLayout);
+    let mut descs = Vec::new();
+    let mut samples = Vec::new();
+    let mut asserts = Vec::new();
+    let mut aligns = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        if ident.to_string().starts_with('_') {
+            samples.push(quote!(#ident: #krate::__private::Zeroable::zeroed()));
+            continue;
+        }
+        descs.push(quote! {
+            #krate::layout::WField {
+                name: stringify!(#ident),
+                ty: <#ty as #krate::tester::WTestable>::inner_type(),
+                offset: ::core::mem::offset_of!(#name, #ident),
+                align: <#ty as #layout>::ALIGN,
+                size: <#ty as #layout>::SIZE,
+                uniform_align: <#ty as #layout>::UNIFORM_ALIGN,
+                uniform_size: <#ty as #layout>::UNIFORM_SIZE,
+            }
+        });
+        let offset = quote!(::core::mem::offset_of!(#name, #ident));
+        asserts.push(quote! {
+            assert!(
+                #offset % <#ty as #layout>::ALIGN == 0,
+                concat!(stringify!(#name), ".", stringify!(#ident), " is misaligned for WGSL")
+            );
+            assert!(
+                ::core::mem::size_of::<#ty>() >= <#ty as #layout>::SIZE,
+                concat!(stringify!(#name), ".", stringify!(#ident), " is smaller than its WGSL type")
+            );
+        });
+        if uniform {
+            asserts.push(quote! {
+                assert!(
+                    #offset % <#ty as #layout>::UNIFORM_ALIGN == 0,
+                    concat!(stringify!(#name), ".", stringify!(#ident), " is misaligned for a WGSL uniform")
+                );
+                assert!(
+                    ::core::mem::size_of::<#ty>() >= <#ty as #layout>::UNIFORM_SIZE,
+                    concat!(stringify!(#name), ".", stringify!(#ident), " is smaller than its WGSL uniform type")
+                );
+            });
+        }
+        aligns.push(quote!(<#ty as #layout>::ALIGN));
+        samples.push(quote!(#ident: #krate::__private::rand::Rng::gen(rng)));
+    }
+
+    Ok(quote! {
+        const _: () = {
+            #(#asserts)*
+            assert!(
+                ::core::mem::size_of::<#name>() % <#name as #layout>::ALIGN == 0,
+                concat!("size of ", stringify!(#name), " is not a multiple of its WGSL alignment")
+            );
+        };
+
+        impl #layout for #name {
+            const ALIGN: usize = {
+                let mut align = 1;
+                #(
+                    if #aligns > align {
+                        align = #aligns;
+                    }
+                )*
+                align
+            };
+            const SIZE: usize = ::core::mem::size_of::<#name>();
+            const UNIFORM_ALIGN: usize = #krate::types::wround_up(16, Self::ALIGN);
+        }
+
+        impl #krate::layout::WgslStruct for #name {
+            const NAME: &'static str = stringify!(#name);
+
+            fn fields() -> ::std::vec::Vec<#krate::layout::WField> {
+                ::std::vec![#(#descs),*]
+            }
+        }
+
+        impl #krate::tester::WTestable for #name {
+            fn wgsl_type() -> #krate::tester::WType {
+                static BODY: ::std::sync::OnceLock<&'static str> = ::std::sync::OnceLock::new();
+                #krate::tester::WType::Struct(BODY.get_or_init(|| {
+                    let body = <#name as #krate::layout::WgslStruct>::wgsl_body(
+                        #krate::layout::WAddressSpace::Storage,
+                    );
+                    ::std::boxed::Box::leak(body.into_boxed_str())
+                }))
+            }
+        }
+
+        impl #krate::tester::impl_prelude::WDistribution<#name>
+            for #krate::tester::impl_prelude::WStandard
+        {
+            fn sample<R: #krate::__private::rand::Rng + ?Sized>(&self, rng: &mut R) -> #name {
+                #name {
+                    #(#samples),*
+                }
+            }
+        }
+    })
+}
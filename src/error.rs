@@ -0,0 +1,112 @@
+use core::fmt;
+
+use crate::{
+    image::Size,
+    modules::warp_perspective::{BorderMode, Interpolation},
+};
+
+/// Errors surfaced by the GPU setup and the warp paths, so callers can fall
+/// back to the CPU implementation instead of aborting.
+#[derive(Debug)]
+pub enum WError {
+    NoAdapter,
+    RequestDevice(wgpu::RequestDeviceError),
+    BufferMap(wgpu::BufferAsyncError),
+    ShaderCompile(String),
+    SizeMismatch {
+        expected: Size,
+        got: Size,
+    },
+    /// The GPU paths can not bind the zero-sized buffers of an empty image
+    EmptyImage(Size),
+    CountMismatch {
+        expected: usize,
+        got: usize,
+    },
+    /// Bytes from or for a GPU buffer have the wrong length or alignment
+    ByteLength {
+        expected: usize,
+        got: usize,
+    },
+    NotEnoughPoints {
+        needed: usize,
+        got: usize,
+    },
+    SingularMatrix,
+    /// The warp backend has no path for this interpolation
+    UnsupportedInterpolation(Interpolation),
+    /// The warp backend has no path for this border mode
+    UnsupportedBorder(BorderMode),
+    /// The workgroup tile exceeds the device's compute limits
+    TileSize {
+        x: u32,
+        y: u32,
+    },
+}
+
+impl fmt::Display for WError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WError::NoAdapter => write!(f, "No suitable GPU adapter found"),
+            WError::RequestDevice(e) => write!(f, "Failed to request device: {}", e),
+            WError::BufferMap(e) => write!(f, "Failed to map buffer: {}", e),
+            WError::ShaderCompile(e) => write!(f, "Failed to compile shader: {}", e),
+            WError::SizeMismatch { expected, got } => {
+                write!(f, "Size mismatch: expected {}, got {}", expected, got)
+            }
+            WError::EmptyImage(size) => write!(f, "Image of size {} is empty", size),
+            WError::CountMismatch { expected, got } => {
+                write!(f, "Count mismatch: expected {}, got {}", expected, got)
+            }
+            WError::ByteLength { expected, got } => {
+                write!(
+                    f,
+                    "Byte length mismatch: expected {}, got {}",
+                    expected, got
+                )
+            }
+            WError::NotEnoughPoints { needed, got } => {
+                write!(f, "Not enough points: needed {}, got {}", needed, got)
+            }
+            WError::SingularMatrix => write!(
+                f,
+                "Determinant is zero. There exists no inverse for this matrix!"
+            ),
+            WError::UnsupportedInterpolation(interp) => {
+                write!(
+                    f,
+                    "{:?} interpolation is not supported by this backend",
+                    interp
+                )
+            }
+            WError::UnsupportedBorder(border) => {
+                write!(f, "{:?} border is not supported by this backend", border)
+            }
+            WError::TileSize { x, y } => {
+                write!(f, "Workgroup tile {}x{} exceeds the device limits", x, y)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WError::RequestDevice(e) => Some(e),
+            WError::BufferMap(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for WError {
+    fn from(e: wgpu::RequestDeviceError) -> Self {
+        WError::RequestDevice(e)
+    }
+}
+
+impl From<wgpu::BufferAsyncError> for WError {
+    fn from(e: wgpu::BufferAsyncError) -> Self {
+        WError::BufferMap(e)
+    }
+}
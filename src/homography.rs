@@ -0,0 +1,512 @@
+use rand::{seq::index::sample, Rng};
+
+use crate::{error::WError, image::PointF, types::WMat3x3Affine};
+
+/// Row major f64 matrix for the estimation itself. The DLT solves the
+/// normal equations `A^T A`, which square the condition number, so the f32
+/// [`WMat3x3Affine`] would lose most of its digits there. Results are
+/// converted at the public boundary.
+type Mat3 = [[f64; 3]; 3];
+
+/// Minimal sample size for a homography.
+const MIN_POINTS: usize = 4;
+
+fn mat3_mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn to_wmat(m: &Mat3) -> WMat3x3Affine {
+    WMat3x3Affine::from_row_major(m.map(|row| row.map(|v| v as f32)))
+}
+
+/// Hartley normalisation: translate the centroid to the origin and scale so
+/// the mean distance from it is `sqrt(2)`. Returns the normalised points and
+/// the (row major) similarity that produced them.
+fn normalize(pts: &[PointF]) -> Result<(Vec<(f64, f64)>, Mat3), WError> {
+    let n = pts.len() as f64;
+    let cx = pts.iter().map(|p| p.x as f64).sum::<f64>() / n;
+    let cy = pts.iter().map(|p| p.y as f64).sum::<f64>() / n;
+    let mean_dist = pts
+        .iter()
+        .map(|p| ((p.x as f64 - cx).powi(2) + (p.y as f64 - cy).powi(2)).sqrt())
+        .sum::<f64>()
+        / n;
+    if mean_dist < f64::EPSILON {
+        return Err(WError::SingularMatrix);
+    }
+    let s = std::f64::consts::SQRT_2 / mean_dist;
+    let t = [[s, 0.0, -s * cx], [0.0, s, -s * cy], [0.0, 0.0, 1.0]];
+    let normalized = pts
+        .iter()
+        .map(|p| (s * (p.x as f64 - cx), s * (p.y as f64 - cy)))
+        .collect();
+    Ok((normalized, t))
+}
+
+/// Whether any three of `pts` lie on a line. Expects normalised points, so
+/// the tolerance on twice the triangle area is absolute.
+fn has_collinear_triple(pts: &[(f64, f64)]) -> bool {
+    let n = pts.len();
+    (0..n).any(|i| {
+        (i + 1..n).any(|j| {
+            (j + 1..n).any(|k| {
+                let (a, b, c) = (pts[i], pts[j], pts[k]);
+                let area = (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+                area.abs() < 1e-6
+            })
+        })
+    })
+}
+
+/// Eigenvector of the smallest eigenvalue of a symmetric matrix, by cyclic
+/// Jacobi rotations, together with the second smallest eigenvalue. That one
+/// is close to zero when the smallest is not unique.
+fn smallest_eigenvector<const N: usize>(mut a: [[f64; N]; N]) -> ([f64; N], f64) {
+    let mut v = [[0.0; N]; N];
+    for (i, row) in v.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+
+    for _ in 0..100 {
+        let off: f64 = (0..N)
+            .flat_map(|p| (p + 1..N).map(move |q| (p, q)))
+            .map(|(p, q)| a[p][q] * a[p][q])
+            .sum();
+        if off < 1e-30 {
+            break;
+        }
+        for p in 0..N {
+            for q in p + 1..N {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for row in a.iter_mut() {
+                    let (akp, akq) = (row[p], row[q]);
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+                let (rp, rq) = (a[p], a[q]);
+                for (k, (apk, aqk)) in rp.iter().zip(rq.iter()).enumerate() {
+                    a[p][k] = c * apk - s * aqk;
+                    a[q][k] = s * apk + c * aqk;
+                }
+                for row in v.iter_mut() {
+                    let (vkp, vkq) = (row[p], row[q]);
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    let mut order: [usize; N] = std::array::from_fn(|i| i);
+    order.sort_by(|&i, &j| a[i][i].total_cmp(&a[j][j]));
+    let mut e = [0.0; N];
+    for (k, e) in e.iter_mut().enumerate() {
+        *e = v[k][order[0]];
+    }
+    let second = order.get(1).map_or(f64::INFINITY, |&i| a[i][i]);
+    (e, second)
+}
+
+/// Row major DLT solution mapping `src` onto `dst`. Fails with
+/// [`WError::SingularMatrix`] for degenerate point sets, whose homography is
+/// not unique or not invertible.
+fn dlt(src: &[PointF], dst: &[PointF]) -> Result<Mat3, WError> {
+    let (src_n, src_t) = normalize(src)?;
+    let (dst_n, dst_t) = normalize(dst)?;
+    // A minimal sample with three points on a line pins down no homography;
+    // with more points only the rank check below can tell
+    if src.len() == MIN_POINTS && (has_collinear_triple(&src_n) || has_collinear_triple(&dst_n)) {
+        return Err(WError::SingularMatrix);
+    }
+
+    // Accumulate A^T A directly instead of building the 2N x 9 matrix A
+    let mut ata = [[0.0; 9]; 9];
+    for (&(x, y), &(u, v)) in src_n.iter().zip(dst_n.iter()) {
+        let rows = [
+            [-x, -y, -1.0, 0.0, 0.0, 0.0, u * x, u * y, u],
+            [0.0, 0.0, 0.0, -x, -y, -1.0, v * x, v * y, v],
+        ];
+        for r in rows.iter() {
+            for i in 0..9 {
+                for j in 0..9 {
+                    ata[i][j] += r[i] * r[j];
+                }
+            }
+        }
+    }
+    let (h, second) = smallest_eigenvector(ata);
+    // More than one null vector: the points do not constrain all 8 degrees
+    // of freedom, e.g. because they all lie on one line
+    let trace: f64 = (0..9).map(|i| ata[i][i]).sum();
+    if second <= 1e-10 * trace {
+        return Err(WError::SingularMatrix);
+    }
+    let hn = [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], h[8]]];
+
+    // Denormalise: H = T_dst^-1 * Hn * T_src
+    let (s, tx, ty) = (dst_t[0][0], dst_t[0][2], dst_t[1][2]);
+    let dst_t_inv = [
+        [1.0 / s, 0.0, -tx / s],
+        [0.0, 1.0 / s, -ty / s],
+        [0.0, 0.0, 1.0],
+    ];
+    let mut m = mat3_mul(&dst_t_inv, &mat3_mul(&hn, &src_t));
+    if m[2][2].abs() < f64::EPSILON {
+        return Err(WError::SingularMatrix);
+    }
+    let scale = m[2][2];
+    for v in m.iter_mut().flatten() {
+        *v /= scale;
+    }
+    Ok(m)
+}
+
+fn check_points(src: &[PointF], dst: &[PointF]) -> Result<(), WError> {
+    if src.len() != dst.len() {
+        return Err(WError::CountMismatch {
+            expected: src.len(),
+            got: dst.len(),
+        });
+    }
+    if src.len() < MIN_POINTS {
+        return Err(WError::NotEnoughPoints {
+            needed: MIN_POINTS,
+            got: src.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Squared distance between `m * src` and `dst`.
+fn reprojection_error(m: &Mat3, src: &PointF, dst: &PointF) -> f64 {
+    let (x, y) = (src.x as f64, src.y as f64);
+    let w = m[2][0] * x + m[2][1] * y + m[2][2];
+    let u = (m[0][0] * x + m[0][1] * y + m[0][2]) / w;
+    let v = (m[1][0] * x + m[1][1] * y + m[1][2]) / w;
+    (u - dst.x as f64).powi(2) + (v - dst.y as f64).powi(2)
+}
+
+/// Homography mapping `src_pts` onto `dst_pts`, from 4 or more point
+/// correspondences (least squares for more than 4), by the normalised DLT.
+///
+/// Like OpenCV's `findHomography` the result maps source to destination.
+/// [`ImageTransform`](crate::modules::warp_perspective::ImageTransform)
+/// expects the opposite direction, so either pass it to
+/// [`ImageTransform::from_forward`](crate::modules::warp_perspective::ImageTransform::from_forward),
+/// which inverts it with [`WMat3x3Affine::try_inverse`], or swap the
+/// arguments. Fails with [`WError::SingularMatrix`] if the points are
+/// degenerate, e.g. all on one line.
+pub fn find_homography(src_pts: &[PointF], dst_pts: &[PointF]) -> Result<WMat3x3Affine, WError> {
+    check_points(src_pts, dst_pts)?;
+    Ok(to_wmat(&dlt(src_pts, dst_pts)?))
+}
+
+/// Affine transform mapping the three `src` points onto the three `dst`
+/// points, like OpenCV's `getAffineTransform`. Fails with
+/// [`WError::SingularMatrix`] if the source points are (nearly) collinear;
+/// they are normalised like in the DLT first, so that test does not depend on
+/// the scale of the coordinates.
+pub fn get_affine_transform(src: &[PointF; 3], dst: &[PointF; 3]) -> Result<WMat3x3Affine, WError> {
+    let (pts, t) = normalize(src)?;
+    if has_collinear_triple(&pts) {
+        return Err(WError::SingularMatrix);
+    }
+    // Both rows of the affine part solve [x y 1] * row = u, so they share
+    // the same system matrix
+    let a: Mat3 = std::array::from_fn(|i| [pts[i].0, pts[i].1, 1.0]);
+    let det = |m: &Mat3| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(&a);
+    // Cramer's rule
+    let solve = |rhs: [f64; 3]| -> [f64; 3] {
+        let mut row = [0.0; 3];
+        for (col, v) in row.iter_mut().enumerate() {
+            let mut m = a;
+            for (r, b) in m.iter_mut().zip(rhs) {
+                r[col] = b;
+            }
+            *v = det(&m) / d;
+        }
+        row
+    };
+    let normalized = [
+        solve(dst.map(|p| p.x as f64)),
+        solve(dst.map(|p| p.y as f64)),
+        [0.0, 0.0, 1.0],
+    ];
+    Ok(to_wmat(&mat3_mul(&normalized, &t)))
+}
+
+/// Options for [`find_homography_ransac`].
+#[derive(Clone, Copy, Debug)]
+pub struct RansacParams {
+    /// Maximum reprojection error, in pixels, for a point to count as inlier
+    pub threshold: f32,
+    pub max_iters: usize,
+    /// Stop once a model this likely to be outlier free has been sampled
+    pub confidence: f64,
+}
+
+impl Default for RansacParams {
+    fn default() -> Self {
+        Self {
+            threshold: 3.0,
+            max_iters: 2000,
+            confidence: 0.995,
+        }
+    }
+}
+
+/// Robust [`find_homography`]. Samples minimal 4 point sets, keeps the model
+/// with the most inliers and refits it on all of them. Returns the homography
+/// together with the inlier mask. The samples are drawn from `rng`; seed it
+/// for reproducible results.
+pub fn find_homography_ransac(
+    src_pts: &[PointF],
+    dst_pts: &[PointF],
+    params: RansacParams,
+    rng: &mut impl Rng,
+) -> Result<(WMat3x3Affine, Vec<bool>), WError> {
+    check_points(src_pts, dst_pts)?;
+    let n = src_pts.len();
+    let threshold = (params.threshold as f64).powi(2);
+    let inliers_of = |m: &Mat3| -> Vec<bool> {
+        src_pts
+            .iter()
+            .zip(dst_pts.iter())
+            .map(|(s, d)| reprojection_error(m, s, d) <= threshold)
+            .collect()
+    };
+
+    let mut best: Option<(Mat3, Vec<bool>, usize)> = None;
+    let mut iters = params.max_iters;
+    let mut i = 0;
+    while i < iters {
+        i += 1;
+        let idx = sample(rng, n, MIN_POINTS);
+        let s: Vec<PointF> = idx.iter().map(|i| src_pts[i]).collect();
+        let d: Vec<PointF> = idx.iter().map(|i| dst_pts[i]).collect();
+        let m = match dlt(&s, &d) {
+            Ok(m) => m,
+            // Degenerate (e.g. collinear) sample
+            Err(_) => continue,
+        };
+        let mask = inliers_of(&m);
+        let count = mask.iter().filter(|&&v| v).count();
+        if best.as_ref().is_none_or(|b| count > b.2) {
+            // Adapt the iteration count to the observed inlier ratio
+            let w = count as f64 / n as f64;
+            let p_fail = 1.0 - w.powi(MIN_POINTS as i32);
+            if p_fail <= f64::EPSILON {
+                iters = i;
+            } else if p_fail < 1.0 {
+                let needed = (1.0 - params.confidence).ln() / p_fail.ln();
+                iters = iters.min(needed.ceil() as usize);
+            }
+            best = Some((m, mask, count));
+        }
+    }
+
+    let (m, mask, count) = best.ok_or(WError::SingularMatrix)?;
+    if count < MIN_POINTS {
+        return Ok((to_wmat(&m), mask));
+    }
+    let s: Vec<PointF> = (0..n).filter(|&i| mask[i]).map(|i| src_pts[i]).collect();
+    let d: Vec<PointF> = (0..n).filter(|&i| mask[i]).map(|i| dst_pts[i]).collect();
+    let refined = dlt(&s, &d).unwrap_or(m);
+    let refined_mask = inliers_of(&refined);
+    Ok((to_wmat(&refined), refined_mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image::Size, modules::warp_perspective::ImageTransform};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const H: Mat3 = [[1.2, 0.1, 5.0], [-0.05, 0.9, -3.0], [0.0008, -0.0004, 1.0]];
+
+    fn project(m: &Mat3, p: &PointF) -> PointF {
+        let (x, y) = (p.x as f64, p.y as f64);
+        let w = m[2][0] * x + m[2][1] * y + m[2][2];
+        PointF::new(
+            ((m[0][0] * x + m[0][1] * y + m[0][2]) / w) as f32,
+            ((m[1][0] * x + m[1][1] * y + m[1][2]) / w) as f32,
+        )
+    }
+
+    fn grid() -> Vec<PointF> {
+        (0..5)
+            .flat_map(|y| (0..6).map(move |x| PointF::new(x as f32 * 40.0, y as f32 * 35.0)))
+            .collect()
+    }
+
+    fn assert_close(m: &WMat3x3Affine) {
+        for (i, row) in H.iter().enumerate() {
+            for (j, v) in row.iter().enumerate() {
+                let got = m.get(j, i) as f64;
+                assert!(
+                    (got - v).abs() < 1e-3 * v.abs().max(1.0),
+                    "{} vs {}",
+                    got,
+                    v
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn four_points_recover_homography() {
+        let src = [
+            PointF::new(0.0, 0.0),
+            PointF::new(200.0, 0.0),
+            PointF::new(200.0, 150.0),
+            PointF::new(0.0, 150.0),
+        ];
+        let dst: Vec<PointF> = src.iter().map(|p| project(&H, p)).collect();
+        assert_close(&find_homography(&src, &dst).unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let p = [PointF::new(0.0, 0.0); 3];
+        assert!(matches!(
+            find_homography(&p, &p),
+            Err(WError::NotEnoughPoints { needed: 4, got: 3 })
+        ));
+        let p = [PointF::new(1.0, 1.0); 4];
+        assert!(matches!(
+            find_homography(&p, &p),
+            Err(WError::SingularMatrix)
+        ));
+    }
+
+    #[test]
+    fn rejects_degenerate_points() {
+        let square = [
+            PointF::new(0.0, 0.0),
+            PointF::new(200.0, 0.0),
+            PointF::new(200.0, 150.0),
+            PointF::new(0.0, 150.0),
+        ];
+        // Three of four on the diagonal
+        let mut three = square;
+        three[1] = PointF::new(100.0, 75.0);
+        assert!(matches!(
+            find_homography(&three, &square),
+            Err(WError::SingularMatrix)
+        ));
+        assert!(matches!(
+            find_homography(&square, &three),
+            Err(WError::SingularMatrix)
+        ));
+
+        let line: Vec<PointF> = (0..8)
+            .map(|i| PointF::new(i as f32 * 10.0, i as f32 * 5.0))
+            .collect();
+        let dst: Vec<PointF> = line.iter().map(|p| project(&H, p)).collect();
+        assert!(matches!(
+            find_homography(&line, &dst),
+            Err(WError::SingularMatrix)
+        ));
+        let mut rng = StdRng::seed_from_u64(7);
+        assert!(find_homography_ransac(&line, &dst, RansacParams::default(), &mut rng).is_err());
+    }
+
+    #[test]
+    fn forward_homography_builds_transform() {
+        let src = grid();
+        let dst: Vec<PointF> = src.iter().map(|p| project(&H, p)).collect();
+        let forward = find_homography(&src, &dst).unwrap();
+        let transform =
+            ImageTransform::from_forward(Size::new(200, 150), Size::new(250, 150), forward)
+                .unwrap();
+        // Maps destination pixels back onto their source points
+        let back = transform.inverse_matrix;
+        let p = dst[7];
+        let w = back.get(0, 2) * p.x + back.get(1, 2) * p.y + back.get(2, 2);
+        let x = (back.get(0, 0) * p.x + back.get(1, 0) * p.y + back.get(2, 0)) / w;
+        let y = (back.get(0, 1) * p.x + back.get(1, 1) * p.y + back.get(2, 1)) / w;
+        assert!((x - src[7].x).abs() < 1e-2 && (y - src[7].y).abs() < 1e-2);
+    }
+
+    #[test]
+    fn ransac_flags_outliers() {
+        let src = grid();
+        let mut dst: Vec<PointF> = src.iter().map(|p| project(&H, p)).collect();
+        let outliers = [3, 11, 17, 22];
+        for &i in outliers.iter() {
+            dst[i].x += 60.0;
+            dst[i].y -= 45.0;
+        }
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let (m, mask) =
+            find_homography_ransac(&src, &dst, RansacParams::default(), &mut rng).unwrap();
+        assert_close(&m);
+        for (i, inlier) in mask.iter().enumerate() {
+            assert_eq!(*inlier, !outliers.contains(&i));
+        }
+    }
+
+    #[test]
+    fn affine_from_three_points() {
+        let m = WMat3x3Affine::get_rotation_matrix_2d(PointF::new(20.0, 10.0), 30.0, 0.8);
+        let map = |p: PointF| {
+            PointF::new(
+                m.get(0, 0) * p.x + m.get(1, 0) * p.y + m.get(2, 0),
+                m.get(0, 1) * p.x + m.get(1, 1) * p.y + m.get(2, 1),
+            )
+        };
+        let src = [
+            PointF::new(0.0, 0.0),
+            PointF::new(50.0, 5.0),
+            PointF::new(10.0, 40.0),
+        ];
+        let got = get_affine_transform(&src, &src.map(map)).unwrap();
+        for x in 0..3 {
+            for y in 0..3 {
+                assert!((got.get(x, y) - m.get(x, y)).abs() < 1e-3);
+            }
+        }
+
+        let collinear = [
+            PointF::new(0.0, 0.0),
+            PointF::new(1.0, 1.0),
+            PointF::new(2.0, 2.0),
+        ];
+        assert!(matches!(
+            get_affine_transform(&collinear, &src),
+            Err(WError::SingularMatrix)
+        ));
+
+        // The collinearity test is relative to the size of the triangle
+        let tiny = src.map(|p| PointF::new(p.x * 1e-4, p.y * 1e-4));
+        let got = get_affine_transform(&tiny, &src).unwrap();
+        assert!((got.get(0, 0) - 1e4).abs() < 1.0 && (got.get(1, 1) - 1e4).abs() < 1.0);
+        let sliver = [
+            PointF::new(0.0, 0.0),
+            PointF::new(1e5, 1e5),
+            PointF::new(2e5, 2e5 + 0.016),
+        ];
+        assert!(matches!(
+            get_affine_transform(&sliver, &src),
+            Err(WError::SingularMatrix)
+        ));
+    }
+}
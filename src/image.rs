@@ -1,10 +1,12 @@
 use bytemuck::{Pod, Zeroable};
 use core::fmt;
+use std::borrow::Cow;
 
 use crate::tester::impl_prelude::*;
+use crate::types::{pack4x8unorm, unpack4x8unorm, WLayout};
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq, Eq, Hash)]
 pub struct Pix {
     pub r: u8,
     pub g: u8,
@@ -30,6 +32,11 @@ impl WDistribution<Pix> for WStandard {
     }
 }
 
+impl WLayout for Pix {
+    const ALIGN: usize = 4;
+    const SIZE: usize = 4;
+}
+
 wtest!(Pix, 256);
 
 impl Pix {
@@ -54,6 +61,42 @@ impl Pix {
             _a: self._a.saturating_add(other._a),
         }
     }
+
+    /// The word `load_pixel` and `store_pixel` in `image/rgb8.wgsl` work on.
+    pub fn to_u32(&self) -> u32 {
+        u32::from_le_bytes([self.r, self.g, self.b, self._a])
+    }
+
+    pub fn from_u32(v: u32) -> Self {
+        let [r, g, b, a] = v.to_le_bytes();
+        Self::new(r, g, b, a)
+    }
+
+    /// Channels in `0.0..=1.0`, i.e. `unpack4x8unorm` of the packed pixel.
+    pub fn to_unorm(&self) -> [f32; 4] {
+        unpack4x8unorm(self.to_u32())
+    }
+
+    /// Inverse of [`Pix::to_unorm`], rounding like `pack4x8unorm`.
+    pub fn from_unorm(v: [f32; 4]) -> Self {
+        Self::from_u32(pack4x8unorm(v))
+    }
+
+    /// The channels `load_pixel` in `image/rgb8.wgsl` returns.
+    pub fn to_vec3(&self) -> [f32; 3] {
+        [self.r as f32, self.g as f32, self.b as f32]
+    }
+
+    /// Equivalent of `store_pixel` in `image/rgb8.wgsl`; the fractional part
+    /// is truncated and alpha is left at zero.
+    pub fn from_vec3(v: [f32; 3]) -> Self {
+        Self::new(
+            v[0].clamp(0.0, 255.0) as u8,
+            v[1].clamp(0.0, 255.0) as u8,
+            v[2].clamp(0.0, 255.0) as u8,
+            0,
+        )
+    }
 }
 
 impl fmt::Display for Pix {
@@ -83,28 +126,358 @@ impl fmt::Display for Point {
     }
 }
 
+/// Sub-pixel variant of [`Point`], e.g. for point correspondences.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PointF {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl PointF {
+    pub fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+}
+
+impl From<Point> for PointF {
+    fn from(p: Point) -> Self {
+        Self::new(p.x as f32, p.y as f32)
+    }
+}
+
+impl fmt::Display for PointF {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+/// A pixel format the warps can work on.
+///
+/// On the GPU every format is stored in `array<u32>` buffers and accessed
+/// through the `load_pixel` and `store_pixel` functions of
+/// [`WPixel::WGSL`], which the warp modules prepend to their shaders. All
+/// interpolation happens on `vec4<f32>` in the format's own scale, e.g.
+/// `0.0..=255.0` for 8-bit channels; unused channels are zero.
+pub trait WPixel: Pod + Default + PartialEq + fmt::Debug + Send + Sync {
+    /// Pixels packed into one `u32` word on the GPU. Rows are padded to whole
+    /// words, see [`WPixel::gpu_stride`].
+    const PIXELS_PER_WORD: usize;
+    /// WGSL for `PIXELS_PER_WORD`, `load_pixel` and `store_pixel` on the
+    /// `input` and `output` bindings
+    const WGSL: &'static str;
+
+    /// Matching pixel type of the `image` crate
+    type Native: image::Pixel;
+
+    /// Channels as `load_pixel` returns them.
+    fn to_vec4(&self) -> [f32; 4];
+
+    /// Same as `store_pixel`: integer channels are clamped to their range
+    /// and truncated.
+    fn from_vec4(v: [f32; 4]) -> Self;
+
+    fn to_native(&self) -> Self::Native;
+
+    fn from_native(p: &Self::Native) -> Self;
+
+    /// Row length in pixels of the GPU layout.
+    fn gpu_stride(width: usize) -> usize {
+        width.div_ceil(Self::PIXELS_PER_WORD) * Self::PIXELS_PER_WORD
+    }
+
+    /// Invocations a warp into an image of `size` dispatches, one per word
+    /// of the GPU layout.
+    fn dispatch_size(size: Size) -> Size {
+        Size::new(size.x.div_ceil(Self::PIXELS_PER_WORD), size.y)
+    }
+}
+
+/// 8-bit RGB; the alpha byte of [`Pix`] is not carried through warps and
+/// comes out as zero.
+pub type Rgb8 = Pix;
+
+impl WPixel for Pix {
+    const PIXELS_PER_WORD: usize = 1;
+    const WGSL: &'static str = include_str!("image/rgb8.wgsl");
+
+    type Native = image::Rgb<u8>;
+
+    fn to_vec4(&self) -> [f32; 4] {
+        let [r, g, b] = self.to_vec3();
+        [r, g, b, 0.0]
+    }
+
+    fn from_vec4(v: [f32; 4]) -> Self {
+        Self::from_vec3([v[0], v[1], v[2]])
+    }
+
+    fn to_native(&self) -> Self::Native {
+        image::Rgb([self.r, self.g, self.b])
+    }
+
+    fn from_native(p: &Self::Native) -> Self {
+        let [r, g, b] = p.0;
+        Self::new(r, g, b, 0)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq, Eq, Hash)]
+pub struct Rgba8 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Rgba8 {
+    pub fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+}
+
+impl WPixel for Rgba8 {
+    const PIXELS_PER_WORD: usize = 1;
+    const WGSL: &'static str = include_str!("image/rgba8.wgsl");
+
+    type Native = image::Rgba<u8>;
+
+    fn to_vec4(&self) -> [f32; 4] {
+        [self.r, self.g, self.b, self.a].map(|c| c as f32)
+    }
+
+    fn from_vec4(v: [f32; 4]) -> Self {
+        let [r, g, b, a] = v.map(|c| c.clamp(0.0, 255.0) as u8);
+        Self::new(r, g, b, a)
+    }
+
+    fn to_native(&self) -> Self::Native {
+        image::Rgba([self.r, self.g, self.b, self.a])
+    }
+
+    fn from_native(p: &Self::Native) -> Self {
+        let [r, g, b, a] = p.0;
+        Self::new(r, g, b, a)
+    }
+}
+
+/// 8-bit gray, e.g. a mask.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq, Eq, Hash)]
+pub struct Gray8(pub u8);
+
+impl WPixel for Gray8 {
+    const PIXELS_PER_WORD: usize = 4;
+    const WGSL: &'static str = include_str!("image/gray8.wgsl");
+
+    type Native = image::Luma<u8>;
+
+    fn to_vec4(&self) -> [f32; 4] {
+        [self.0 as f32, 0.0, 0.0, 0.0]
+    }
+
+    fn from_vec4(v: [f32; 4]) -> Self {
+        Self(v[0].clamp(0.0, 255.0) as u8)
+    }
+
+    fn to_native(&self) -> Self::Native {
+        image::Luma([self.0])
+    }
+
+    fn from_native(p: &Self::Native) -> Self {
+        Self(p.0[0])
+    }
+}
+
+/// 16-bit gray, e.g. a depth map.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq, Eq, Hash)]
+pub struct Gray16(pub u16);
+
+impl WPixel for Gray16 {
+    const PIXELS_PER_WORD: usize = 2;
+    const WGSL: &'static str = include_str!("image/gray16.wgsl");
+
+    type Native = image::Luma<u16>;
+
+    fn to_vec4(&self) -> [f32; 4] {
+        [self.0 as f32, 0.0, 0.0, 0.0]
+    }
+
+    fn from_vec4(v: [f32; 4]) -> Self {
+        Self(v[0].clamp(0.0, 65535.0) as u16)
+    }
+
+    fn to_native(&self) -> Self::Native {
+        image::Luma([self.0])
+    }
+
+    fn from_native(p: &Self::Native) -> Self {
+        Self(p.0[0])
+    }
+}
+
+/// 32-bit float gray, e.g. a disparity map. Values are not clamped.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq)]
+pub struct GrayF32(pub f32);
+
+impl WPixel for GrayF32 {
+    const PIXELS_PER_WORD: usize = 1;
+    const WGSL: &'static str = include_str!("image/grayf32.wgsl");
+
+    type Native = image::Luma<f32>;
+
+    fn to_vec4(&self) -> [f32; 4] {
+        [self.0, 0.0, 0.0, 0.0]
+    }
+
+    fn from_vec4(v: [f32; 4]) -> Self {
+        Self(v[0])
+    }
+
+    fn to_native(&self) -> Self::Native {
+        image::Luma([self.0])
+    }
+
+    fn from_native(p: &Self::Native) -> Self {
+        Self(p.0[0])
+    }
+}
+
+/// 32-bit float RGB. Values are not clamped.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq)]
+pub struct RgbF32 {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl RgbF32 {
+    pub fn new(r: f32, g: f32, b: f32) -> Self {
+        Self { r, g, b }
+    }
+}
+
+impl WPixel for RgbF32 {
+    const PIXELS_PER_WORD: usize = 1;
+    const WGSL: &'static str = include_str!("image/rgbf32.wgsl");
+
+    type Native = image::Rgb<f32>;
+
+    fn to_vec4(&self) -> [f32; 4] {
+        [self.r, self.g, self.b, 0.0]
+    }
+
+    fn from_vec4(v: [f32; 4]) -> Self {
+        Self::new(v[0], v[1], v[2])
+    }
+
+    fn to_native(&self) -> Self::Native {
+        image::Rgb([self.r, self.g, self.b])
+    }
+
+    fn from_native(p: &Self::Native) -> Self {
+        let [r, g, b] = p.0;
+        Self::new(r, g, b)
+    }
+}
+
+/// `image` crate buffer holding the same pixels as an [`Image<P>`].
+pub type NativeImage<P> = image::ImageBuffer<
+    <P as WPixel>::Native,
+    Vec<<<P as WPixel>::Native as image::Pixel>::Subpixel>,
+>;
+
 #[derive(Clone, Debug)]
-pub struct Image {
-    pub data: Vec<Pix>,
+pub struct Image<P = Pix> {
+    pub data: Vec<P>,
     pub size: Size,
 }
 
-impl Image {
+impl<P: WPixel> Image<P> {
     pub fn new(size: Size) -> Self {
         Self {
-            data: vec![Pix::default(); size.x * size.y],
+            data: vec![P::default(); size.x * size.y],
             size,
         }
     }
 
-    pub fn get(&self, x: usize, y: usize) -> &Pix {
-        &self.data[y * self.size.x as usize + x]
+    pub fn get(&self, x: usize, y: usize) -> &P {
+        &self.data[y * self.size.x + x]
     }
 
-    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut Pix {
-        &mut self.data[y * self.size.x as usize + x]
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut P {
+        &mut self.data[y * self.size.x + x]
     }
 
+    pub fn to_native(&self) -> NativeImage<P> {
+        let mut im = NativeImage::<P>::new(self.size.x as u32, self.size.y as u32);
+        for (x, y, pixel) in im.enumerate_pixels_mut() {
+            *pixel = self.get(x as usize, y as usize).to_native();
+        }
+        im
+    }
+
+    pub fn from_native(im: &NativeImage<P>) -> Self {
+        Self {
+            data: im.pixels().map(P::from_native).collect(),
+            size: Size::new(im.width() as usize, im.height() as usize),
+        }
+    }
+
+    /// Number of pixels in the GPU layout, padding included.
+    pub(crate) fn gpu_len(&self) -> usize {
+        P::gpu_stride(self.size.x) * self.size.y
+    }
+
+    /// `data` with every row padded to whole `u32` words, as the shaders
+    /// index it. Only formats with several pixels per word need a copy.
+    pub(crate) fn gpu_data(&self) -> Cow<'_, [P]> {
+        let stride = P::gpu_stride(self.size.x);
+        if stride == self.size.x {
+            return Cow::Borrowed(&self.data);
+        }
+        let mut data = vec![P::default(); self.gpu_len()];
+        for (dst, src) in data
+            .chunks_exact_mut(stride)
+            .zip(self.data.chunks_exact(self.size.x))
+        {
+            dst[..src.len()].copy_from_slice(src);
+        }
+        Cow::Owned(data)
+    }
+
+    /// Inverse of [`Image::gpu_data`]; `data` must hold exactly
+    /// [`Image::gpu_len`] pixels.
+    pub(crate) fn copy_from_gpu(&mut self, data: &[P]) {
+        let stride = P::gpu_stride(self.size.x);
+        if stride == self.size.x {
+            self.data.copy_from_slice(data);
+            return;
+        }
+        for (dst, src) in self
+            .data
+            .chunks_exact_mut(self.size.x)
+            .zip(data.chunks_exact(stride))
+        {
+            dst.copy_from_slice(&src[..dst.len()]);
+        }
+    }
+
+    /// [`Image::copy_from_gpu`] that takes over `data` when there is no
+    /// padding to strip.
+    pub(crate) fn set_gpu_data(&mut self, data: Vec<P>) {
+        if P::gpu_stride(self.size.x) == self.size.x {
+            self.data = data;
+        } else {
+            self.copy_from_gpu(&data);
+        }
+    }
+}
+
+impl Image {
     pub fn rgb_image(&self) -> Result<image::RgbImage, Box<dyn std::error::Error>> {
         TryInto::<image::RgbImage>::try_into(self)
     }
@@ -113,11 +486,59 @@ impl Image {
 impl TryInto<image::RgbImage> for &Image {
     type Error = Box<dyn std::error::Error>;
     fn try_into(self) -> Result<image::RgbImage, Self::Error> {
-        let mut im = image::RgbImage::new(self.size.x as u32, self.size.y as u32);
-        for (x, y, pixel) in im.enumerate_pixels_mut() {
-            let p = self.get(x as usize, y as usize);
-            *pixel = image::Rgb([p.r.try_into()?, p.g.try_into()?, p.b.try_into()?]);
+        Ok(self.to_native())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gpu_data_pads_rows_to_words() {
+        let mut im = Image::<Gray8>::new(Size::new(5, 2));
+        for (i, p) in im.data.iter_mut().enumerate() {
+            *p = Gray8(i as u8 + 1);
+        }
+        let padded = im.gpu_data();
+        assert_eq!(padded.len(), 16);
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&padded[..8]),
+            [1, 2, 3, 4, 5, 0, 0, 0]
+        );
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&padded[8..]),
+            [6, 7, 8, 9, 10, 0, 0, 0]
+        );
+
+        let mut back = Image::<Gray8>::new(im.size);
+        back.set_gpu_data(padded.into_owned());
+        assert_eq!(back.data, im.data);
+
+        let rgb = Image::<Pix>::new(Size::new(3, 3));
+        assert!(matches!(rgb.gpu_data(), Cow::Borrowed(_)));
+        assert_eq!(Gray16::dispatch_size(Size::new(5, 2)), Size::new(3, 2));
+    }
+
+    #[test]
+    fn native_round_trip_keeps_every_channel() {
+        let mut im = Image::<Rgba8>::new(Size::new(2, 1));
+        im.data[1] = Rgba8::new(1, 2, 3, 4);
+        let native = im.to_native();
+        assert_eq!(*native.get_pixel(1, 0), image::Rgba([1, 2, 3, 4]));
+        assert_eq!(Image::<Rgba8>::from_native(&native).data, im.data);
+
+        let mut depth = Image::<Gray16>::new(Size::new(1, 2));
+        depth.data[1] = Gray16(40000);
+        assert_eq!(
+            Image::<Gray16>::from_native(&depth.to_native()).data,
+            depth.data
+        );
+
+        for v in [0.0, 17.5, 255.0, 300.0] {
+            let v4 = [v, v, v, v];
+            assert_eq!(Gray8::from_vec4(v4).to_vec4()[0], v.min(255.0).trunc());
+            assert_eq!(GrayF32::from_vec4(v4).to_vec4()[0], v);
         }
-        Ok(im)
     }
 }
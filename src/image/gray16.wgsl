@@ -0,0 +1,15 @@
+// `Gray16`: two pixels per word, the leftmost in the low half. A word is
+// only ever written by the invocation that owns both of its pixels.
+
+const PIXELS_PER_WORD: u32 = 2u;
+
+fn load_pixel(i: u32) -> vec4<f32> {
+    let v = (input[i / 2u] >> ((i % 2u) * 16u)) & 0xFFFFu;
+    return vec4<f32>(f32(v), 0.0, 0.0, 0.0);
+}
+
+fn store_pixel(i: u32, c: vec4<f32>) {
+    let shift = (i % 2u) * 16u;
+    let v = u32(clamp(c.x, 0.0, 65535.0));
+    output[i / 2u] = (output[i / 2u] & ~(0xFFFFu << shift)) | (v << shift);
+}
@@ -0,0 +1,15 @@
+// `Gray8`: four pixels per word, the leftmost in the lowest byte. A word is
+// only ever written by the invocation that owns all four of its pixels.
+
+const PIXELS_PER_WORD: u32 = 4u;
+
+fn load_pixel(i: u32) -> vec4<f32> {
+    let v = (input[i / 4u] >> ((i % 4u) * 8u)) & 0xFFu;
+    return vec4<f32>(f32(v), 0.0, 0.0, 0.0);
+}
+
+fn store_pixel(i: u32, c: vec4<f32>) {
+    let shift = (i % 4u) * 8u;
+    let v = u32(clamp(c.x, 0.0, 255.0));
+    output[i / 4u] = (output[i / 4u] & ~(0xFFu << shift)) | (v << shift);
+}
@@ -0,0 +1,11 @@
+// `GrayF32`: one float per word, interpolated without clamping.
+
+const PIXELS_PER_WORD: u32 = 1u;
+
+fn load_pixel(i: u32) -> vec4<f32> {
+    return vec4<f32>(bitcast<f32>(input[i]), 0.0, 0.0, 0.0);
+}
+
+fn store_pixel(i: u32, c: vec4<f32>) {
+    output[i] = bitcast<u32>(c.x);
+}
@@ -0,0 +1,17 @@
+// `Pix`: r, g and b in the low three bytes of a word. The fourth byte is
+// unused; it reads as zero and is written as zero.
+
+const PIXELS_PER_WORD: u32 = 1u;
+
+fn unpack_rgba8(v: u32) -> vec4<f32> {
+    return vec4<f32>(f32(v & 0xFFu), f32((v >> 8u) & 0xFFu), f32((v >> 16u) & 0xFFu), f32(v >> 24u));
+}
+
+fn load_pixel(i: u32) -> vec4<f32> {
+    return vec4<f32>(unpack_rgba8(input[i]).xyz, 0.0);
+}
+
+fn store_pixel(i: u32, c: vec4<f32>) {
+    let v = vec3<u32>(clamp(c.xyz, vec3<f32>(0.0), vec3<f32>(255.0)));
+    output[i] = v.x | (v.y << 8u) | (v.z << 16u);
+}
@@ -0,0 +1,16 @@
+// `Rgba8`: one byte per channel, r in the lowest byte.
+
+const PIXELS_PER_WORD: u32 = 1u;
+
+fn unpack_rgba8(v: u32) -> vec4<f32> {
+    return vec4<f32>(f32(v & 0xFFu), f32((v >> 8u) & 0xFFu), f32((v >> 16u) & 0xFFu), f32(v >> 24u));
+}
+
+fn load_pixel(i: u32) -> vec4<f32> {
+    return unpack_rgba8(input[i]);
+}
+
+fn store_pixel(i: u32, c: vec4<f32>) {
+    let v = vec4<u32>(clamp(c, vec4<f32>(0.0), vec4<f32>(255.0)));
+    output[i] = v.x | (v.y << 8u) | (v.z << 16u) | (v.w << 24u);
+}
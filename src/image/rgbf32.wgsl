@@ -0,0 +1,16 @@
+// `RgbF32`: three consecutive floats per pixel, interpolated without
+// clamping.
+
+const PIXELS_PER_WORD: u32 = 1u;
+
+fn load_pixel(i: u32) -> vec4<f32> {
+    let w = 3u * i;
+    return vec4<f32>(bitcast<f32>(input[w]), bitcast<f32>(input[w + 1u]), bitcast<f32>(input[w + 2u]), 0.0);
+}
+
+fn store_pixel(i: u32, c: vec4<f32>) {
+    let w = 3u * i;
+    output[w] = bitcast<u32>(c.x);
+    output[w + 1u] = bitcast<u32>(c.y);
+    output[w + 2u] = bitcast<u32>(c.z);
+}
@@ -0,0 +1,145 @@
+use crate::{
+    tester::WTestable,
+    types::{wround_up, WLayout},
+};
+
+pub use rustwarp_derive::WgslStruct;
+
+/// The WGSL address space a struct is laid out for. `Storage` follows the
+/// std430-like rules of storage buffers, `Uniform` additionally rounds array
+/// strides and alignments up to 16 bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WAddressSpace {
+    Storage,
+    Uniform,
+}
+
+/// One field of a [`WgslStruct`], as generated by `#[derive(WgslStruct)]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WField {
+    pub name: &'static str,
+    /// WGSL type of the field, e.g. `vec2<u32>`
+    pub ty: &'static str,
+    /// Byte offset of the field in the Rust struct
+    pub offset: usize,
+    /// [`WLayout`] of the field type
+    pub align: usize,
+    pub size: usize,
+    pub uniform_align: usize,
+    pub uniform_size: usize,
+}
+
+impl WField {
+    fn align_size(&self, space: WAddressSpace) -> (usize, usize) {
+        match space {
+            WAddressSpace::Storage => (self.align, self.size),
+            WAddressSpace::Uniform => (self.uniform_align, self.uniform_size),
+        }
+    }
+}
+
+/// A `#[repr(C)]` struct shared with WGSL. Implement it with
+/// `#[derive(WgslStruct)]`, which also provides `WLayout`, `WTestable` and
+/// `WDistribution` so the struct can be used with `wtest!`.
+///
+/// The derive asserts at compile time that every field sits at an offset its
+/// WGSL type may have in a storage buffer; `#[wgsl(uniform)]` additionally
+/// checks the uniform rules. This does not compile, because `b` would have to
+/// start at byte 16:
+///
+/// ```compile_fail
+/// use rustwarp::{wvec3, WgslStruct};
+///
+/// #[repr(C)]
+/// #[derive(WgslStruct)]
+/// struct Misaligned {
+///     a: f32,
+///     b: wvec3!(f32, 0),
+/// }
+/// ```
+pub trait WgslStruct: WTestable + WLayout + Sized {
+    const NAME: &'static str;
+
+    fn fields() -> Vec<WField>;
+
+    /// The field list of the WGSL struct. Fields get an explicit `@size` when
+    /// the Rust struct carries more padding after them than WGSL would
+    /// insert.
+    fn wgsl_body(space: WAddressSpace) -> String {
+        let fields = Self::fields();
+        let struct_align = match space {
+            WAddressSpace::Storage => Self::ALIGN,
+            WAddressSpace::Uniform => Self::UNIFORM_ALIGN,
+        };
+        let members: Vec<String> = fields
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                let (_, size) = f.align_size(space);
+                // Where the next member (or the end of the struct) starts in
+                // Rust, and where WGSL would put it without help
+                let (next, next_align) = match fields.get(i + 1) {
+                    Some(n) => (n.offset, n.align_size(space).0),
+                    None => (std::mem::size_of::<Self>(), struct_align),
+                };
+                match wround_up(next_align, f.offset + size) == next {
+                    true => format!("{}: {}", f.name, f.ty),
+                    false => format!("@size({}) {}: {}", next - f.offset, f.name, f.ty),
+                }
+            })
+            .collect();
+        members.join(",\n    ")
+    }
+
+    /// Complete WGSL declaration, to be prepended to shader sources.
+    fn wgsl_struct(space: WAddressSpace) -> String {
+        format!(
+            "struct {} {{\n    {}\n}}\n",
+            Self::NAME,
+            Self::wgsl_body(space)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::WMat3x3Affine;
+
+    #[test]
+    fn layout_follows_spec() {
+        assert_eq!((<wvec3!(f32, 4)>::ALIGN, <wvec3!(f32, 4)>::SIZE), (16, 12));
+        assert_eq!((<wvec2!(u32, 0)>::ALIGN, <wvec2!(u32, 0)>::SIZE), (8, 8));
+        assert_eq!((WMat3x3Affine::ALIGN, WMat3x3Affine::SIZE), (16, 48));
+        assert_eq!((<[f32; 5]>::SIZE, <[f32; 5]>::UNIFORM_SIZE), (20, 80));
+        assert_eq!(<[wvec3!(f32, 4); 2]>::SIZE, 32);
+    }
+
+    #[repr(C)]
+    #[derive(WgslStruct)]
+    #[wgsl(uniform)]
+    struct Padded {
+        a: wvec3!(f32, 4),
+        b: f32,
+        _pad: [u8; 12],
+    }
+
+    #[test]
+    fn derive_emits_matching_wgsl() {
+        use crate::modules::warp_perspective::ImageTransform;
+        assert_eq!(
+            ImageTransform::wgsl_struct(WAddressSpace::Storage),
+            "struct ImageTransform {\n    src_dimensions: vec2<u32>,\n    \
+             dst_dimensions: vec2<u32>,\n    inverse_matrix: mat3x3<f32>,\n    \
+             border_value: vec4<f32>,\n    border: u32\n}\n"
+        );
+        assert_eq!(
+            (Padded::ALIGN, Padded::SIZE, Padded::UNIFORM_ALIGN),
+            (16, 32, 16)
+        );
+        assert_eq!(
+            Padded::wgsl_body(WAddressSpace::Storage),
+            "@size(16) a: vec3<f32>,\n    b: f32"
+        );
+    }
+}
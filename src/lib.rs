@@ -1,8 +1,24 @@
+extern crate self as rustwarp;
+
 #[macro_use]
 pub mod types;
 #[macro_use]
 pub mod setup;
 
+pub mod error;
+pub mod homography;
 pub mod image;
+pub mod layout;
 pub mod modules;
+pub mod profiler;
 pub mod tester;
+pub mod trace;
+
+pub use layout::WgslStruct;
+
+/// Used by the code `#[derive(WgslStruct)]` generates.
+#[doc(hidden)]
+pub mod __private {
+    pub use bytemuck::Zeroable;
+    pub use rand;
+}
@@ -1,2 +1,4 @@
 #[tokio::main]
-async fn main() {}
+async fn main() {
+    env_logger::init();
+}
@@ -1,2 +1,3 @@
 pub mod multiple_warp;
 pub mod warp_perspective;
+pub mod warp_texture;
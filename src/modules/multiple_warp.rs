@@ -1,333 +1,405 @@
+use std::{collections::HashMap, time::Instant};
+
 use crate::{
-    image::{Image, Pix, Size},
-    setup::WState,
-    types::WMat3x3Affine,
+    error::WError,
+    image::{Image, Pix, Size, WPixel},
+    profiler::WProfiler,
+    setup::{WBuffer, WState},
+    trace::WTrace,
 };
-use bytemuck::{Pod, Zeroable};
-use wgpu::util::DeviceExt;
-
-use crate::tester::impl_prelude::*;
-
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub enum Interpolation {
-    None,
-    Bilinear,
-}
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq)]
-pub struct ImageTransform {
-    pub dimensions: wvec2!(u32, 8),
-    pub inverse_matrix: WMat3x3Affine,
+use super::warp_perspective::{warp_shader, WarpPipelines};
+pub use super::warp_perspective::{BorderMode, ImageTransform, Interpolation, WarpStats};
+
+/// Per-image offsets into the concatenated pixel buffers. `x` indexes the
+/// input, `y` the output, both in pixels of the padded GPU layout.
+type BatchOffset = wvec2!(u32, 0);
+
+/// Buffers for one batch shape, keyed by the number of images and the total
+/// input and output pixel counts.
+struct BatchBuffers<'a, P: WPixel> {
+    transform: WBuffer<'a, ImageTransform>,
+    offsets: WBuffer<'a, BatchOffset>,
+    src: WBuffer<'a, P>,
+    dst: WBuffer<'a, P>,
+    bind_group: wgpu::BindGroup,
 }
 
-impl ImageTransform {
-    pub fn new(dimensions: Size, matrix: WMat3x3Affine) -> Self {
-        let mut s = Self::default();
-        s.dimensions.x = dimensions.x as u32;
-        s.dimensions.y = dimensions.y as u32;
-        s.inverse_matrix = matrix;
-        s
-    }
-}
-
-impl WTestable for ImageTransform {
-    fn wgsl_type() -> WType {
-        WType::Struct("a: vec2<u32>, b: mat3x3<f32>")
-    }
-}
-
-impl WDistribution<ImageTransform> for WStandard {
-    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> ImageTransform {
-        ImageTransform {
-            dimensions: rng.gen(),
-            inverse_matrix: rng.gen(),
+type BatchKey = (usize, usize, usize);
+
+impl<'a, P: WPixel> BatchBuffers<'a, P> {
+    fn new(state: &'a WState, layout: &wgpu::BindGroupLayout, key: BatchKey) -> Self {
+        let (n, src_len, dst_len) = key;
+        let storage = wgpu::BufferUsages::STORAGE;
+        let transform = WBuffer::new(state, "Transform buffer", n, storage);
+        let offsets = WBuffer::new(state, "Offset buffer", n, storage);
+        let src = WBuffer::new(state, "Input image buffer", src_len, storage);
+        let dst = WBuffer::new(state, "Output image buffer", dst_len, storage);
+        let bind_group = wgpu_bind_group!(
+            state.device,
+            layout,
+            [
+                (0, transform.as_binding()),
+                (1, src.as_binding()),
+                (2, dst.as_binding()),
+                (3, offsets.as_binding())
+            ]
+        );
+        Self {
+            transform,
+            offsets,
+            src,
+            dst,
+            bind_group,
         }
     }
 }
 
-wtest!(ImageTransform, 256);
-
-pub struct WarpPerspectiveGpu {
-    pub state: WState,
-    cs_module: wgpu::ShaderModule,
-    pub transform: Vec<ImageTransform>,
+/// Batched GPU perspective warp.
+///
+/// One [`MultipleWarp::warp`] call uploads N images of arbitrary sizes and N
+/// transforms, warps them all in a single dispatch (one z slice per image)
+/// and returns the N warped images. Pipelines are compiled on first use;
+/// buffers are
+/// cached per batch shape like in
+/// [`WarpPerspective`](super::warp_perspective::WarpPerspective), and all
+/// images of a batch share the pixel format `P`.
+pub struct MultipleWarp<'a, P: WPixel = Pix> {
+    state: &'a WState,
     pub interp: Interpolation,
+    /// When set, every warp records its CPU and GPU spans here
+    pub trace: Option<WTrace>,
 
-    pub src_bytes: Vec<u8>,
-    pub transform_bytes: Vec<u8>,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipelines: WarpPipelines,
 
-    pub src: Vec<Image>,
-    pub dst: Vec<Image>,
-}
-
-impl WarpPerspectiveGpu {
-    pub fn new(
-        state: WState,
-        transform: Vec<ImageTransform>,
-        interp: Interpolation,
-        src: Vec<Image>,
-        dst: Vec<Image>,
-    ) -> Self {
-        let cs_module = state
-            .device
-            .create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("Image transform shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("multiple_warp.wgsl").into()),
-            });
-
-        // Make relevant bytes
-        let src_bytes = src
-            .iter()
-            .map(|im| bytemuck::cast_slice(im.data.as_ref()))
-            .flatten()
-            .copied()
-            .collect::<Vec<u8>>();
+    profiler: WProfiler<'a>,
 
-        let transform_bytes = transform
-            .iter()
-            .map(|t| bytemuck::bytes_of(t))
-            .flatten()
-            .copied()
-            .collect::<Vec<u8>>();
+    buffers: HashMap<BatchKey, BatchBuffers<'a, P>>,
+}
 
-        Self {
+impl<'a, P: WPixel> MultipleWarp<'a, P> {
+    pub async fn new(state: &'a WState, interp: Interpolation) -> Result<Self, WError> {
+        let shader = warp_shader::<P>(state, include_str!("multiple_warp.wgsl"));
+        let (bind_group_layout, compute_pipeline_layout) = state
+            .validate(|device| {
+                let bind_group_layout = wgpu_bind_group_layout_compute!(
+                    "Multiple warp layout",
+                    device,
+                    [(0, true), (1, true), (2, false), (3, true)]
+                );
+                let compute_pipeline_layout =
+                    wgpu_compute_pipeline_layout!(device, &[&bind_group_layout]);
+                (bind_group_layout, compute_pipeline_layout)
+            })
+            .await?;
+        let pipelines =
+            WarpPipelines::new("Multiple warp pipeline", shader, compute_pipeline_layout);
+
+        let profiler = WProfiler::new(state, 1);
+
+        Ok(Self {
             state,
-            cs_module,
-            transform,
             interp,
-            src_bytes,
-            transform_bytes,
-            src,
-            dst,
-        }
+            trace: None,
+            bind_group_layout,
+            pipelines,
+            profiler,
+            buffers: HashMap::new(),
+        })
     }
 
-    pub async fn render_pass(&mut self) {
-        warp_perspective_gpu(
-            &mut self.state,
-            &self.cs_module,
-            &self.transform,
-            self.interp,
-            &mut self.src_bytes,
-            &self.transform_bytes,
-            &mut self.dst,
-        )
-        .await;
+    /// Warps `src[i]` with `transforms[i]` for every `i` and returns the
+    /// outputs with the timings of the whole batch. The images may all have
+    /// different sizes; output `i` is sized by `transforms[i]` and starts out
+    /// zeroed, which is what a [`BorderMode::Transparent`] pixel keeps.
+    pub async fn warp(
+        &mut self,
+        transforms: &[ImageTransform],
+        src: &[Image<P>],
+    ) -> Result<(Vec<Image<P>>, WarpStats), WError> {
+        let mut dst: Vec<Image<P>> = transforms
+            .iter()
+            .map(|t| Image::new(t.dst_size()))
+            .collect();
+        let stats = self.warp_into(transforms, src, &mut dst).await?;
+        Ok((dst, stats))
     }
-}
 
-pub async fn warp_perspective_gpu(
-    state: &mut WState,
-    cs_module: &wgpu::ShaderModule,
-    _transform: &Vec<ImageTransform>,
-    interp: Interpolation,
-    src_bytes: &mut Vec<u8>,
-    transform_bytes: &Vec<u8>,
-    // src: &Vec<Image>,
-    dst: &mut Vec<Image>,
-) {
-    let entry_point = match interp {
-        Interpolation::None => "interpolation_none",
-        Interpolation::Bilinear => "interpolation_bilinear",
-    };
-
-    let features = state.device.features();
-    let query_set = if features.contains(wgpu::Features::TIMESTAMP_QUERY) {
-        Some(state.device.create_query_set(&wgpu::QuerySetDescriptor {
-            count: 2,
-            ty: wgpu::QueryType::Timestamp,
-            label: None,
-        }))
-    } else {
-        None
-    };
-
-    // Make relevant buffers
-    let transform_buf = state
-        .device
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Transform buffer"),
-            contents: &transform_bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-        });
-    let dst_buf = state.device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Output image buffer"),
-        size: src_bytes.len() as u64,
-        usage: wgpu::BufferUsages::STORAGE
-            | wgpu::BufferUsages::COPY_SRC
-            | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
-    let out_buf = state.device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Output image buffer"),
-        size: src_bytes.len() as u64,
-        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
-
-    let query_buf = state
-        .device
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Query buffer"),
-            contents: &[0; 16],
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-        });
-
-    // Bind group layout
-    let bind_group_layout =
-        state
-            .device
-            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            // TODO: Change to uniform?
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                ],
-            });
-
-    // Pipeline
-    let compute_pipeline_layout =
-        state
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Computer Pipeline"),
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
-    let pipeline = state
-        .device
-        .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("Main pipeline"),
-            layout: Some(&compute_pipeline_layout),
-            module: &cs_module,
-            entry_point,
-        });
-
-    let mut i = 0;
-    loop {
-        i += 1;
-        src_bytes[i] = 255;
-
-        let start = std::time::Instant::now();
-        let src_buf = state
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Input image buffer"),
-                contents: &src_bytes,
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-            });
-
-        // Bind group
-        let bind_group = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: transform_buf.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: src_buf.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: dst_buf.as_entire_binding(),
-                },
-            ],
-        });
+    /// Like [`MultipleWarp::warp`], but writes into existing images. As in
+    /// [`WarpPerspective::warp`](super::warp_perspective::WarpPerspective::warp),
+    /// pixels that map outside a [`BorderMode::Transparent`] source keep
+    /// what `dst[i]` already holds.
+    pub async fn warp_into(
+        &mut self,
+        transforms: &[ImageTransform],
+        src: &[Image<P>],
+        dst: &mut [Image<P>],
+    ) -> Result<WarpStats, WError> {
+        for len in [src.len(), dst.len()] {
+            if transforms.len() != len {
+                return Err(WError::CountMismatch {
+                    expected: len,
+                    got: transforms.len(),
+                });
+            }
+        }
+        if src.is_empty() {
+            return Ok(WarpStats::default());
+        }
+
+        for ((t, src), dst) in transforms.iter().zip(src.iter()).zip(dst.iter()) {
+            t.check_sizes(src.size, dst.size)?;
+        }
+        let pipeline = self.pipelines.get(self.state, self.interp, false).await?;
+
+        let mut offsets = Vec::with_capacity(src.len());
+        let (mut src_len, mut dst_len) = (0, 0);
+        for (src, dst) in src.iter().zip(dst.iter()) {
+            offsets.push(BatchOffset::new(src_len as u32, dst_len as u32));
+            src_len += src.gpu_len();
+            dst_len += dst.gpu_len();
+        }
+        let max_size = Size::new(
+            dst.iter().map(|im| im.size.x).max().unwrap_or(0),
+            dst.iter().map(|im| im.size.y).max().unwrap_or(0),
+        );
+
+        let key = (src.len(), src_len, dst_len);
+        let state = self.state;
+
+        let started = Instant::now();
+        let buffers: &BatchBuffers<P> = self
+            .buffers
+            .entry(key)
+            .or_insert_with(|| BatchBuffers::new(state, &self.bind_group_layout, key));
+        let created = started.elapsed();
+
+        let start = Instant::now();
+        buffers.transform.upload(transforms)?;
+        buffers.offsets.upload(&offsets)?;
+        for (im, offset) in src.iter().zip(offsets.iter()) {
+            buffers.src.write_range(offset.x as usize, &im.gpu_data())?;
+        }
+        // Pixels the shader skips must not leak in from the previous batch;
+        // with a transparent border they keep what `dst` already holds
+        let transparent = transforms
+            .iter()
+            .any(|t| t.border_mode() == BorderMode::Transparent);
+        if transparent {
+            for (im, offset) in dst.iter().zip(offsets.iter()) {
+                buffers.dst.write_range(offset.y as usize, &im.gpu_data())?;
+            }
+        }
+        let upload = start.elapsed();
 
         // Encoder
+        let start = Instant::now();
         let mut encoder = state.device.create_command_encoder(&Default::default());
-        if let Some(query_set) = &query_set {
-            encoder.write_timestamp(query_set, 0);
+        if !transparent {
+            encoder.clear_buffer(buffers.dst.buffer(), 0, None);
         }
         {
+            let mut encoder = self.profiler.scope("warp", &mut encoder);
             let mut cpass = encoder.begin_compute_pass(&Default::default());
-            cpass.set_pipeline(&pipeline);
-            cpass.set_bind_group(0, &bind_group, &[]);
-            cpass.dispatch_workgroups(dst[0].size.x as u32, dst[0].size.y as u32, dst.len() as u32);
-        }
-        if let Some(query_set) = &query_set {
-            encoder.write_timestamp(query_set, 1);
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, &buffers.bind_group, &[]);
+            let (x, y) = state.tile_size.workgroups(P::dispatch_size(max_size));
+            cpass.dispatch_workgroups(x, y, src.len() as u32);
         }
+        self.profiler.resolve(&mut encoder);
+        state.queue.submit(Some(encoder.finish()));
+        let dispatch = start.elapsed();
 
         // Get data out of device
-        encoder.copy_buffer_to_buffer(&dst_buf, 0, &out_buf, 0, src_bytes.len() as u64);
-        if let Some(query_set) = &query_set {
-            encoder.resolve_query_set(query_set, 0..2, &query_buf, 0);
+        let start = Instant::now();
+        let (data, timings) =
+            futures::join!(state.read_buffer(&buffers.dst), self.profiler.finish());
+        let data = data?;
+        let mut offset = 0;
+        for dst in dst.iter_mut() {
+            let len = dst.gpu_len();
+            dst.copy_from_gpu(&data[offset..offset + len]);
+            offset += len;
         }
-        state.queue.submit(Some(encoder.finish()));
+        let download = start.elapsed();
+        let kernel = WarpStats::kernel_time(&timings?, "warp");
+
+        let stats = WarpStats {
+            buffers: created,
+            upload,
+            dispatch,
+            kernel,
+            download,
+        };
+        log::debug!("batch warp of {} images: {:?}", src.len(), stats);
+        if let Some(trace) = &self.trace {
+            trace.record_warp("multiple_warp", started, &stats);
+        }
+        Ok(stats)
+    }
+}
 
-        let out_slice = out_buf.slice(..);
-        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-        out_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-
-        // More queries
-        let query_slice = query_buf.slice(..);
-        let _query_future = query_slice.map_async(wgpu::MapMode::Read, |_| ());
-        println!("pre-poll {:?}", std::time::Instant::now());
-        state.device.poll(wgpu::Maintain::Wait);
-
-        println!("post-poll {:?}", std::time::Instant::now());
-        if let Some(Ok(())) = receiver.receive().await {
-            let data_raw = &*out_slice.get_mapped_range();
-            let data: &[Pix] = bytemuck::cast_slice(data_raw);
-            let chunk_size = dst[0].size.x * dst[0].size.y;
-            for (dst, data) in dst.iter_mut().zip(data.chunks(chunk_size)) {
-                dst.data.copy_from_slice(data);
+/// One-shot convenience wrapper around [`MultipleWarp`].
+pub async fn warp_perspective_gpu<P: WPixel>(
+    state: &WState,
+    transforms: &[ImageTransform],
+    interp: Interpolation,
+    src: &[Image<P>],
+) -> Result<(Vec<Image<P>>, WarpStats), WError> {
+    MultipleWarp::new(state, interp)
+        .await?
+        .warp(transforms, src)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::warp_perspective::{warp_perspective_cpu, WarpPerspective};
+    use crate::types::WMat3x3Affine;
+    use pollster::FutureExt;
+
+    fn test_image(size: Size, seed: usize) -> Image {
+        let mut im = Image::new(size);
+        for (i, p) in im.data.iter_mut().enumerate() {
+            let i = i + seed;
+            *p = Pix::new(i as u8, (i * 3) as u8, (i * 7) as u8, 0);
+        }
+        im
+    }
+
+    fn assert_close(cpu: &Image, gpu: &Image, what: &str) {
+        assert_eq!(cpu.size, gpu.size);
+        for (c, g) in cpu.data.iter().zip(gpu.data.iter()) {
+            for (c, g) in c.to_vec3().iter().zip(g.to_vec3().iter()) {
+                assert!((c - g).abs() <= 2.0, "{}: {} vs {}", what, c, g);
             }
         }
-        if features.contains(wgpu::Features::TIMESTAMP_QUERY) {
-            let ts_period = state.queue.get_timestamp_period();
-            let ts_data_raw = &*query_slice.get_mapped_range();
-            let ts_data: &[u64] = bytemuck::cast_slice(ts_data_raw);
-            println!(
-                "compute shader elapsed: {:?}ms",
-                (ts_data[1] - ts_data[0]) as f64 * ts_period as f64 * 1e-6
-            );
+    }
+
+    #[test]
+    fn gpu_matches_cpu_for_every_interpolation() {
+        let state = WState::new().block_on().unwrap();
+        let src = [test_image(Size::new(16, 12), 0)];
+        let matrix = WMat3x3Affine::from_row_major([
+            [0.7, 0.1, 0.35],
+            [-0.05, 0.9, 0.2],
+            [0.0005, 0.0, 1.0],
+        ]);
+        let transforms = [ImageTransform::new(src[0].size, Size::new(20, 10), matrix)
+            .with_border(BorderMode::Reflect101)];
+        let mut engine = MultipleWarp::new(&state, Interpolation::None)
+            .block_on()
+            .unwrap();
+
+        for interp in Interpolation::ALL {
+            let mut cpu = Image::new(transforms[0].dst_size());
+            warp_perspective_cpu(&transforms[0], interp, &src[0], &mut cpu);
+            engine.interp = interp;
+            let (gpu, _) = engine.warp(&transforms, &src).block_on().unwrap();
+            assert_close(&cpu, &gpu[0], &format!("{:?}", interp));
         }
+    }
 
-        println!("Elapsed: {:?}", start.elapsed());
+    #[test]
+    fn batches_images_of_different_sizes() {
+        let state = WState::new().block_on().unwrap();
+        let mut engine = MultipleWarp::new(&state, Interpolation::Bilinear)
+            .block_on()
+            .unwrap();
+        let shrink =
+            WMat3x3Affine::from_row_major([[1.3, 0.1, 0.5], [0.0, 1.2, 0.25], [0.0, 0.0005, 1.0]]);
+        let shift =
+            WMat3x3Affine::from_row_major([[1.0, 0.0, -3.0], [0.0, 1.0, 2.0], [0.0, 0.0, 1.0]]);
+
+        // The second batch has the same shape, so it reuses the buffers of the
+        // first. With a transparent border every pixel the shader skips must
+        // come out zeroed rather than left over from the first batch.
+        for (seed, border) in [
+            (0, BorderMode::constant(Pix::new(10, 20, 30, 0))),
+            (100, BorderMode::Transparent),
+        ] {
+            let src = [
+                test_image(Size::new(16, 12), seed),
+                test_image(Size::new(9, 7), seed + 50),
+            ];
+            let transforms = [
+                ImageTransform::new(src[0].size, Size::new(20, 10), shrink).with_border(border),
+                ImageTransform::new(src[1].size, Size::new(11, 13), shift).with_border(border),
+            ];
+            let (gpu, _) = engine.warp(&transforms, &src).block_on().unwrap();
+            assert_eq!(gpu.len(), 2);
+            for (i, (t, im)) in transforms.iter().zip(src.iter()).enumerate() {
+                let mut cpu = Image::new(t.dst_size());
+                warp_perspective_cpu(t, Interpolation::Bilinear, im, &mut cpu);
+                assert_close(&cpu, &gpu[i], &format!("image {} with {:?}", i, border));
+            }
+        }
 
-        out_buf.unmap();
-        query_buf.unmap();
+        let (gpu, _) = warp_perspective_gpu(
+            &state,
+            &[ImageTransform::new(Size::new(4, 4), Size::new(4, 4), shift)],
+            Interpolation::None,
+            &[test_image(Size::new(4, 4), 0)],
+        )
+        .block_on()
+        .unwrap();
+        assert_eq!(gpu[0].size, Size::new(4, 4));
+        assert!(engine
+            .warp(&[], &[test_image(Size::new(4, 4), 0)])
+            .block_on()
+            .is_err());
+        // Zero-sized storage buffers can not be bound
+        for (src, dst) in [
+            (Size::new(0, 4), Size::new(4, 4)),
+            (Size::new(4, 4), Size::new(4, 0)),
+        ] {
+            let transform = ImageTransform::new(src, dst, shift);
+            assert!(matches!(
+                engine.warp(&[transform], &[test_image(src, 0)]).block_on(),
+                Err(WError::EmptyImage(_))
+            ));
+        }
+    }
 
-        for (i, d) in dst.iter().enumerate() {
-            d.rgb_image()
-                .unwrap()
-                .save(format!("outputs/output_{}.png", i))
+    #[test]
+    fn border_modes_match_single_warp() {
+        let state = WState::new().block_on().unwrap();
+        let mut single = WarpPerspective::new(&state, Interpolation::Bilinear)
+            .block_on()
+            .unwrap();
+        let mut batch = MultipleWarp::new(&state, Interpolation::Bilinear)
+            .block_on()
+            .unwrap();
+        let src = [test_image(Size::new(9, 7), 0)];
+        // Reaches past every edge of the source
+        let matrix =
+            WMat3x3Affine::from_row_major([[1.4, 0.2, -3.5], [0.1, 1.3, -2.5], [0.0, 0.0, 1.0]]);
+
+        for border in [
+            BorderMode::constant(Pix::new(10, 20, 30, 0)),
+            BorderMode::Replicate,
+            BorderMode::Reflect,
+            BorderMode::Reflect101,
+            BorderMode::Wrap,
+            BorderMode::Transparent,
+        ] {
+            let transforms =
+                [ImageTransform::new(src[0].size, Size::new(12, 10), matrix).with_border(border)];
+            // Transparent pixels keep whatever the destination held before
+            let background = test_image(transforms[0].dst_size(), 99);
+            let mut expected = background.clone();
+            single
+                .warp(&transforms[0], &src[0], &mut expected)
+                .block_on()
+                .unwrap();
+            let mut dst = [background];
+            batch
+                .warp_into(&transforms, &src, &mut dst)
+                .block_on()
                 .unwrap();
+            assert_eq!(expected.data, dst[0].data, "{:?}", border);
         }
     }
 }
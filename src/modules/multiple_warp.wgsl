@@ -1,11 +1,17 @@
+// `ImageTransform` is generated from the Rust struct by `WgslStruct` and
+// prepended by the host. `border` is the border mode and `border_value` the
+// constant border pixel. `PIXELS_PER_WORD`, `load_pixel` and `store_pixel`
+// come from the pixel format (`WPixel::WGSL`), also prepended by the host;
+// pixels are interpolated as `vec4<f32>` in the format's scale, which is also
+// the scale of `border_value`.
+// The `BORDER_*` constants, interpolation weights and `border_interpolate`
+// are prepended from `warp_common.wgsl`.
 
-
-struct ImageTransform {
-    odim: vec2<u32>,
-    tmatrix: mat3x3<f32>,
-}
-
-type RGBPixel = u32;
+// Workgroup tile; the host replaces both with `WState::tile_size` and
+// dispatches ceil(width / (TILE_X * PIXELS_PER_WORD)) x ceil(height / TILE_Y)
+// workgroups
+const TILE_X: u32 = 16u;
+const TILE_Y: u32 = 16u;
 
 // TODO: Change to uniform?
 @group(0)
@@ -14,79 +20,241 @@ var<storage, read> transform: array<ImageTransform>;
 
 @group(0)
 @binding(1)
-var<storage, read> input: array<RGBPixel>;
+var<storage, read> input: array<u32>;
 
 @group(0)
 @binding(2)
-var<storage, read_write> output: array<RGBPixel>;
+var<storage, read_write> output: array<u32>;
 
-fn ind(x: u32, y: u32, width: u32, height: u32, offset: u32) -> u32 {
-    return (height * width * offset) + y * width + x;
+// x: input offset, y: output offset, in pixels of the padded layout
+@group(0)
+@binding(3)
+var<storage, read> offsets: array<vec2<u32>>;
+
+fn ind(x: u32, y: u32, width: u32, offset: u32) -> u32 {
+    return offset + y * width + x;
 }
 
-fn red(pixel: RGBPixel) -> u32 {
-    return pixel & 0xFFu;
+fn dst_ind(t: ImageTransform, offset: u32, pos: vec2<u32>) -> u32 {
+    return ind(pos.x, pos.y, row_stride(t.dst_dimensions.x), offset);
 }
 
-fn green(pixel: RGBPixel) -> u32 {
-    return (pixel >> 8u) & 0xFFu;
+fn src_pos(t: ImageTransform, x: i32, y: i32) -> vec2<i32> {
+    let mode = t.border;
+    return vec2<i32>(border_interpolate(x, i32(t.src_dimensions.x), mode), border_interpolate(y, i32(t.src_dimensions.y), mode));
 }
 
-fn blue(pixel: RGBPixel) -> u32 {
-    return (pixel >> 16u) & 0xFFu;
+// Source pixel after border handling. Transparent borders have to be checked
+// by the caller since there is nothing to return for them.
+fn fetch(t: ImageTransform, offset: u32, p: vec2<i32>) -> vec4<f32> {
+    if p.x < 0 || p.y < 0 {
+        return t.border_value;
+    }
+    return load_pixel(ind(u32(p.x), u32(p.y), row_stride(t.src_dimensions.x), offset));
 }
 
-fn vec3f_from_pixel(pixel: RGBPixel) -> vec3<f32> {
-    return vec3<f32>(f32(red(pixel)), f32(green(pixel)), f32(blue(pixel)));
+fn map_pos(t: ImageTransform, dst: vec2<u32>) -> vec2<f32> {
+    var pos = vec3<f32>(f32(dst.x), f32(dst.y), 1.0);
+    pos = t.inverse_matrix * pos;
+    return pos.xy / pos.z;
 }
 
-fn pixel_from_vec3u(v: vec3<u32>) -> RGBPixel {
-    return (u32(v.x)) | (u32(v.y) << 8u) | (u32(v.z) << 16u);
+fn warp_none(t: ImageTransform, offset: vec2<u32>, pos: vec2<u32>) {
+    let fpos = floor(map_pos(t, pos));
+    let p = src_pos(t, i32(fpos.x), i32(fpos.y));
+
+    if is_outside(p) && t.border == BORDER_TRANSPARENT {
+        return;
+    }
+
+    store_pixel(dst_ind(t, offset.y, pos), fetch(t, offset.x, p));
 }
 
-@compute
-@workgroup_size(1)
-fn interpolation_none(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let tx = transform[global_id.z].odim.x;
-    let ty = transform[global_id.z].odim.y;
+fn warp_bilinear(t: ImageTransform, offset: vec2<u32>, pos: vec2<u32>) {
+    // Floating point position
+    let fpos = map_pos(t, pos);
+    // Floored floating point position
+    let rpos = floor(fpos);
+    //  Floored integer position
+    let ipos = vec2<i32>(i32(rpos.x), i32(rpos.y));
+    // Fractional part
+    let fpart = fpos - rpos;
+
+    let s0 = src_pos(t, ipos.x, ipos.y);
+    let s1 = src_pos(t, ipos.x + 1, ipos.y);
+    let s2 = src_pos(t, ipos.x, ipos.y + 1);
+    let s3 = src_pos(t, ipos.x + 1, ipos.y + 1);
+
+    if t.border == BORDER_TRANSPARENT && (is_outside(s0) || is_outside(s1) || is_outside(s2) || is_outside(s3)) {
+        return;
+    }
+
+    let p0 = (1.0 - fpart.x) * (1.0 - fpart.y) * fetch(t, offset.x, s0);
+    let p1 = fpart.x * (1.0 - fpart.y) * fetch(t, offset.x, s1);
+    let p2 = (1.0 - fpart.x) * fpart.y * fetch(t, offset.x, s2);
+    let p3 = fpart.x * fpart.y * fetch(t, offset.x, s3);
+
+    store_pixel(dst_ind(t, offset.y, pos), p0 + p1 + p2 + p3);
+}
+
+fn warp_bicubic(t: ImageTransform, offset: vec2<u32>, pos: vec2<u32>) {
+    let fpos = map_pos(t, pos);
+    let rpos = floor(fpos);
+    let ipos = vec2<i32>(i32(rpos.x), i32(rpos.y));
+    let wx = bicubic_weights(fpos.x - rpos.x);
+    let wy = bicubic_weights(fpos.y - rpos.y);
+
+    var sum = vec4<f32>(0.0);
+    for (var j = 0; j < 4; j++) {
+        var row = vec4<f32>(0.0);
+        for (var i = 0; i < 4; i++) {
+            let s = src_pos(t, ipos.x - 1 + i, ipos.y - 1 + j);
+            if is_outside(s) && t.border == BORDER_TRANSPARENT {
+                return;
+            }
+            row += wx[i] * fetch(t, offset.x, s);
+        }
+        sum += wy[j] * row;
+    }
+
+    store_pixel(dst_ind(t, offset.y, pos), sum);
+}
+
+fn warp_lanczos4(t: ImageTransform, offset: vec2<u32>, pos: vec2<u32>) {
+    let fpos = map_pos(t, pos);
+    let rpos = floor(fpos);
+    let ipos = vec2<i32>(i32(rpos.x), i32(rpos.y));
+    var wx = lanczos4_weights(fpos.x - rpos.x);
+    var wy = lanczos4_weights(fpos.y - rpos.y);
 
-    var pos = vec3<f32>(f32(global_id.x), f32(global_id.y), 1.0);
-    pos = transform[global_id.z].tmatrix * pos;
-    pos /= pos.z;
+    var sum = vec4<f32>(0.0);
+    for (var j = 0; j < 8; j++) {
+        var row = vec4<f32>(0.0);
+        for (var i = 0; i < 8; i++) {
+            let s = src_pos(t, ipos.x - 3 + i, ipos.y - 3 + j);
+            if is_outside(s) && t.border == BORDER_TRANSPARENT {
+                return;
+            }
+            row += wx[i] * fetch(t, offset.x, s);
+        }
+        sum += wy[j] * row;
+    }
+
+    store_pixel(dst_ind(t, offset.y, pos), sum);
+}
+
+fn warp_area(t: ImageTransform, offset: vec2<u32>, pos: vec2<u32>) {
+    let m = t.inverse_matrix;
+    let h = m * vec3<f32>(f32(pos.x) + 0.5, f32(pos.y) + 0.5, 1.0);
+    let c = h.xy / h.z;
+    // Jacobian of the homography at the pixel centre
+    let dx = (m[0].xy - c * m[0].z) / h.z;
+    let dy = (m[1].xy - c * m[1].z) / h.z;
+    let ext = clamp(0.5 * (abs(dx) + abs(dy)), vec2<f32>(0.5), vec2<f32>(MAX_AREA_HALF_EXTENT));
+    let lo = c - ext;
+    let hi = c + ext;
+    let first = vec2<i32>(i32(floor(lo.x)), i32(floor(lo.y)));
+    let n = clamp(vec2<i32>(i32(ceil(hi.x)), i32(ceil(hi.y))) - first, vec2<i32>(0), vec2<i32>(66));
 
-    if pos.x < 0.0 || pos.x >= f32(ty) || pos.y < 0.0 || pos.y >= f32(ty) {
+    var sum = vec4<f32>(0.0);
+    var wsum = 0.0;
+    for (var j = first.y; j < first.y + n.y; j++) {
+        let wy = area_weight(j, lo.y, hi.y);
+        for (var i = first.x; i < first.x + n.x; i++) {
+            let w = wy * area_weight(i, lo.x, hi.x);
+            let s = src_pos(t, i, j);
+            if is_outside(s) && t.border == BORDER_TRANSPARENT {
+                return;
+            }
+            sum += w * fetch(t, offset.x, s);
+            wsum += w;
+        }
+    }
+
+    // Degenerate footprint, e.g. a point at infinity
+    if wsum <= 0.0 {
+        if t.border != BORDER_TRANSPARENT {
+            store_pixel(dst_ind(t, offset.y, pos), t.border_value);
+        }
         return;
     }
+    store_pixel(dst_ind(t, offset.y, pos), sum / wsum);
+}
 
-    let src_ind = ind(u32(pos.x), u32(pos.y), tx, ty, global_id.z);
-    let dst_ind = ind(global_id.x, global_id.y, tx, ty, global_id.z);
+// Every invocation covers the `PIXELS_PER_WORD` pixels of one output word, so
+// no two invocations write the same word
 
-    output[dst_ind] = input[src_ind];
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_none(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let t = transform[global_id.z];
+    let offset = offsets[global_id.z];
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The dispatch covers the largest image in the batch, in whole tiles
+        if pos.x >= t.dst_dimensions.x || pos.y >= t.dst_dimensions.y {
+            return;
+        }
+        warp_none(t, offset, pos);
+    }
 }
 
-// @compute
-// @workgroup_size(1)
-// fn interpolation_bilinear(@builtin(global_invocation_id) global_id: vec3<u32>) {
-//     // Floating point position
-//     var fpos = vec3<f32>(f32(global_id.x), f32(global_id.y), 1.0);
-//     fpos = transform.tmatrix * fpos;
-//     fpos /= fpos.z;
-
-//     if fpos.x < 0.0 || fpos.x >= f32(transform.odim.x) || fpos.y < 0.0 || fpos.y >= f32(transform.odim.y) {
-//         return;
-//     }
-
-//     // Floored floating point position
-//     let rpos = vec2<f32>(floor(fpos.x), floor(fpos.y));
-//     //  Floored integer position
-//     let ipos = vec2<u32>(u32(rpos.x), u32(rpos.y));
-//     // Fractional part
-//     let fpart = vec2<f32>(fpos.x - rpos.x, fpos.y - rpos.y);
-
-//     let p0 = (1.0 - fpart.x) * (1.0 - fpart.y) * vec3f_from_pixel(input[ind(ipos.x, ipos.y, transform.odim.x)]);
-//     let p1 = fpart.x * (1.0 - fpart.y) * vec3f_from_pixel(input[ind(ipos.x + 1u, ipos.y, transform.odim.x)]);
-//     let p2 = (1.0 - fpart.x) * fpart.y * vec3f_from_pixel(input[ind(ipos.x, ipos.y + 1u, transform.odim.x)]);
-//     let p3 = fpart.x * fpart.y * vec3f_from_pixel(input[ind(ipos.x + 1u, ipos.y + 1u, transform.odim.x)]);
-
-//     output[ind(global_id.x, global_id.y, transform.odim.x)] = pixel_from_vec3u(vec3<u32>(p0 + p1 + p2 + p3));
-// }
\ No newline at end of file
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_bilinear(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let t = transform[global_id.z];
+    let offset = offsets[global_id.z];
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The dispatch covers the largest image in the batch, in whole tiles
+        if pos.x >= t.dst_dimensions.x || pos.y >= t.dst_dimensions.y {
+            return;
+        }
+        warp_bilinear(t, offset, pos);
+    }
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_bicubic(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let t = transform[global_id.z];
+    let offset = offsets[global_id.z];
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The dispatch covers the largest image in the batch, in whole tiles
+        if pos.x >= t.dst_dimensions.x || pos.y >= t.dst_dimensions.y {
+            return;
+        }
+        warp_bicubic(t, offset, pos);
+    }
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_lanczos4(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let t = transform[global_id.z];
+    let offset = offsets[global_id.z];
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The dispatch covers the largest image in the batch, in whole tiles
+        if pos.x >= t.dst_dimensions.x || pos.y >= t.dst_dimensions.y {
+            return;
+        }
+        warp_lanczos4(t, offset, pos);
+    }
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_area(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let t = transform[global_id.z];
+    let offset = offsets[global_id.z];
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The dispatch covers the largest image in the batch, in whole tiles
+        if pos.x >= t.dst_dimensions.x || pos.y >= t.dst_dimensions.y {
+            return;
+        }
+        warp_area(t, offset, pos);
+    }
+}
@@ -0,0 +1,101 @@
+// Helpers shared by `warp_perspective.wgsl` and `multiple_warp.wgsl`. The
+// host prepends this after the pixel format, so `PIXELS_PER_WORD` is known.
+
+// Must match `BorderMode::to_gpu`
+const BORDER_CONSTANT: u32 = 0u;
+const BORDER_REPLICATE: u32 = 1u;
+const BORDER_REFLECT: u32 = 2u;
+const BORDER_REFLECT_101: u32 = 3u;
+const BORDER_WRAP: u32 = 4u;
+const BORDER_TRANSPARENT: u32 = 5u;
+
+// Rows are padded to whole words
+fn row_stride(width: u32) -> u32 {
+    return (width + PIXELS_PER_WORD - 1u) / PIXELS_PER_WORD * PIXELS_PER_WORD;
+}
+
+const PI: f32 = 3.14159265358979;
+
+// Cubic convolution weights with a = -0.75 for taps at -1, 0, 1, 2
+fn bicubic_weights(x: f32) -> vec4<f32> {
+    let a = -0.75;
+    let w0 = ((a * (x + 1.0) - 5.0 * a) * (x + 1.0) + 8.0 * a) * (x + 1.0) - 4.0 * a;
+    let w1 = ((a + 2.0) * x - (a + 3.0)) * x * x + 1.0;
+    let w2 = ((a + 2.0) * (1.0 - x) - (a + 3.0)) * (1.0 - x) * (1.0 - x) + 1.0;
+    return vec4<f32>(w0, w1, w2, 1.0 - w0 - w1 - w2);
+}
+
+fn sinc(x: f32) -> f32 {
+    if abs(x) < 1e-6 {
+        return 1.0;
+    }
+    return sin(PI * x) / (PI * x);
+}
+
+// Normalised Lanczos weights for taps at -3 ..= 4
+fn lanczos4_weights(x: f32) -> array<f32, 8> {
+    var w: array<f32, 8>;
+    var sum = 0.0;
+    for (var i = 0; i < 8; i++) {
+        let d = x + 3.0 - f32(i);
+        w[i] = sinc(d) * sinc(d / 4.0);
+        sum += w[i];
+    }
+    for (var i = 0; i < 8; i++) {
+        w[i] /= sum;
+    }
+    return w;
+}
+
+fn modulo(a: i32, n: i32) -> i32 {
+    return ((a % n) + n) % n;
+}
+
+// Maps `p` onto [0, len), or -1 when there is no source pixel for it
+fn border_interpolate(p: i32, len: i32, mode: u32) -> i32 {
+    if p >= 0 && p < len {
+        return p;
+    }
+    // An empty source has nothing to replicate, reflect or wrap
+    if len <= 0 {
+        return -1;
+    }
+    switch mode {
+        case BORDER_REPLICATE: {
+            return clamp(p, 0, len - 1);
+        }
+        case BORDER_REFLECT: {
+            if len == 1 {
+                return 0;
+            }
+            let period = 2 * len;
+            let q = modulo(p, period);
+            return select(q, period - q - 1, q >= len);
+        }
+        case BORDER_REFLECT_101: {
+            if len == 1 {
+                return 0;
+            }
+            let period = 2 * len - 2;
+            let q = modulo(p, period);
+            return select(q, period - q, q >= len);
+        }
+        case BORDER_WRAP: {
+            return modulo(p, len);
+        }
+        default: {
+            return -1;
+        }
+    }
+}
+
+fn is_outside(p: vec2<i32>) -> bool {
+    return p.x < 0 || p.y < 0;
+}
+
+const MAX_AREA_HALF_EXTENT: f32 = 32.0;
+
+// Overlap of source pixel [i, i + 1) with [lo, hi]
+fn area_weight(i: i32, lo: f32, hi: f32) -> f32 {
+    return max(0.0, min(f32(i + 1), hi) - max(f32(i), lo));
+}
@@ -1,298 +1,1231 @@
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
 use crate::{
-    image::{Image, Pix, Size},
-    setup::WState,
+    error::WError,
+    image::{Image, Pix, Size, WPixel},
+    layout::{WAddressSpace, WgslStruct},
+    profiler::WProfiler,
+    setup::{WBuffer, WState},
+    trace::WTrace,
     types::WMat3x3Affine,
 };
 use bytemuck::{Pod, Zeroable};
-use wgpu::util::DeviceExt;
 
 use crate::tester::impl_prelude::*;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Interpolation {
     None,
     Bilinear,
+    /// 4x4 cubic convolution with `a = -0.75`, as in OpenCV's `INTER_CUBIC`
+    Bicubic,
+    /// 8x8 Lanczos window, as in OpenCV's `INTER_LANCZOS4`
+    Lanczos4,
+    /// Coverage-weighted average over the source footprint of each destination
+    /// pixel, estimated from the local Jacobian of the transform. Use it when
+    /// the warp shrinks the image; it degrades to a one pixel box otherwise.
+    Area,
+}
+
+impl Interpolation {
+    pub const ALL: [Interpolation; 5] = [
+        Interpolation::None,
+        Interpolation::Bilinear,
+        Interpolation::Bicubic,
+        Interpolation::Lanczos4,
+        Interpolation::Area,
+    ];
+
+    pub fn entry_point(&self) -> &'static str {
+        match self {
+            Interpolation::None => "interpolation_none",
+            Interpolation::Bilinear => "interpolation_bilinear",
+            Interpolation::Bicubic => "interpolation_bicubic",
+            Interpolation::Lanczos4 => "interpolation_lanczos4",
+            Interpolation::Area => "interpolation_area",
+        }
+    }
+}
+
+/// What a warp does with source coordinates that fall outside the image.
+/// Mirrors OpenCV's `BORDER_*` flags; the letters show how `abcdefgh` is
+/// extended to the left and right.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BorderMode {
+    /// `iiiiii|abcdefgh|iiiiiii` with a fixed pixel `i`, given as the
+    /// [`WPixel::to_vec4`] channels of the image's format, e.g. `-1.0` for an
+    /// invalid disparity in a [`GrayF32`](crate::image::GrayF32) map. Build it
+    /// from a pixel with [`BorderMode::constant`].
+    Constant([f32; 4]),
+    /// `aaaaaa|abcdefgh|hhhhhhh`
+    Replicate,
+    /// `fedcba|abcdefgh|hgfedcb`
+    Reflect,
+    /// `gfedcb|abcdefgh|gfedcba`
+    Reflect101,
+    /// `cdefgh|abcdefgh|abcdefg`
+    Wrap,
+    /// Leave the destination pixel untouched
+    Transparent,
+}
+
+impl Default for BorderMode {
+    fn default() -> Self {
+        BorderMode::Constant([0.0; 4])
+    }
+}
+
+impl BorderMode {
+    /// Constant border of pixel `value`.
+    pub fn constant<P: WPixel>(value: P) -> Self {
+        BorderMode::Constant(value.to_vec4())
+    }
+
+    /// `(mode, constant value)` as stored in `ImageTransform.border` and
+    /// `ImageTransform.border_value`. Must match the `BORDER_*` constants in
+    /// the shaders.
+    pub fn to_gpu(&self) -> (u32, [f32; 4]) {
+        match self {
+            BorderMode::Constant(value) => (0, *value),
+            BorderMode::Replicate => (1, [0.0; 4]),
+            BorderMode::Reflect => (2, [0.0; 4]),
+            BorderMode::Reflect101 => (3, [0.0; 4]),
+            BorderMode::Wrap => (4, [0.0; 4]),
+            BorderMode::Transparent => (5, [0.0; 4]),
+        }
+    }
+
+    pub fn from_gpu(mode: u32, value: [f32; 4]) -> Self {
+        match mode {
+            1 => BorderMode::Replicate,
+            2 => BorderMode::Reflect,
+            3 => BorderMode::Reflect101,
+            4 => BorderMode::Wrap,
+            5 => BorderMode::Transparent,
+            _ => BorderMode::Constant(value),
+        }
+    }
+
+    /// Maps coordinate `p` onto `[0, len)`, or `None` when there is no source
+    /// pixel for it (constant and transparent borders). Same as
+    /// `border_interpolate` in the shaders.
+    pub fn interpolate(&self, p: i32, len: i32) -> Option<i32> {
+        if p >= 0 && p < len {
+            return Some(p);
+        }
+        // An empty source has nothing to replicate, reflect or wrap
+        if len <= 0 {
+            return None;
+        }
+        match self {
+            BorderMode::Replicate => Some(p.clamp(0, len - 1)),
+            BorderMode::Reflect => {
+                if len == 1 {
+                    return Some(0);
+                }
+                let period = 2 * len;
+                let q = p.rem_euclid(period);
+                Some(if q >= len { period - q - 1 } else { q })
+            }
+            BorderMode::Reflect101 => {
+                if len == 1 {
+                    return Some(0);
+                }
+                let period = 2 * len - 2;
+                let q = p.rem_euclid(period);
+                Some(if q >= len { period - q } else { q })
+            }
+            BorderMode::Wrap => Some(p.rem_euclid(len)),
+            BorderMode::Constant(_) | BorderMode::Transparent => None,
+        }
+    }
+
+    /// Source pixel at `(x, y)` after border handling, `None` for transparent
+    /// borders.
+    pub fn fetch<P: WPixel>(&self, src: &Image<P>, x: i32, y: i32) -> Option<P> {
+        let (w, h) = (src.size.x as i32, src.size.y as i32);
+        match (self.interpolate(x, w), self.interpolate(y, h)) {
+            (Some(x), Some(y)) => Some(*src.get(x as usize, y as usize)),
+            _ => match self {
+                BorderMode::Constant(value) => Some(P::from_vec4(*value)),
+                _ => None,
+            },
+        }
+    }
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq, WgslStruct)]
 pub struct ImageTransform {
-    pub dimensions: wvec2!(u32, 8),
+    /// Size of the image that is sampled from
+    pub src_dimensions: wvec2!(u32, 0),
+    /// Size of the image that is written to, i.e. the dispatch extent
+    pub dst_dimensions: wvec2!(u32, 0),
+    /// Maps destination pixel coordinates back into the source image
     pub inverse_matrix: WMat3x3Affine,
+    /// Constant border pixel of [`BorderMode::to_gpu`], in the channels of
+    /// the image's format
+    pub border_value: wvec4!(f32, 0),
+    /// Border mode of [`BorderMode::to_gpu`]
+    pub border: u32,
+    pub _pad: [u8; 12],
 }
 
 impl ImageTransform {
-    pub fn new(dimensions: Size, matrix: WMat3x3Affine) -> Self {
+    pub fn new(src_size: Size, dst_size: Size, matrix: WMat3x3Affine) -> Self {
         let mut s = Self::default();
-        s.dimensions.x = dimensions.x as u32;
-        s.dimensions.y = dimensions.y as u32;
+        s.src_dimensions.set(src_size.x as u32, src_size.y as u32);
+        s.dst_dimensions.set(dst_size.x as u32, dst_size.y as u32);
         s.inverse_matrix = matrix;
         s
     }
+
+    /// Build from a source to destination matrix, e.g. one of the
+    /// [`WMat3x3Affine`] builders or [`get_affine_transform`](crate::homography::get_affine_transform).
+    pub fn from_forward(
+        src_size: Size,
+        dst_size: Size,
+        forward: WMat3x3Affine,
+    ) -> Result<Self, WError> {
+        Ok(Self::new(src_size, dst_size, forward.try_inverse()?))
+    }
+
+    pub fn with_border(mut self, border: BorderMode) -> Self {
+        let (mode, [r, g, b, a]) = border.to_gpu();
+        self.border = mode;
+        self.border_value.set(r, g, b, a);
+        self
+    }
+
+    pub fn border_mode(&self) -> BorderMode {
+        let v = self.border_value;
+        BorderMode::from_gpu(self.border, [v.x, v.y, v.z, v.w])
+    }
+
+    pub fn src_size(&self) -> Size {
+        Size::new(
+            self.src_dimensions.x as usize,
+            self.src_dimensions.y as usize,
+        )
+    }
+
+    pub fn dst_size(&self) -> Size {
+        Size::new(
+            self.dst_dimensions.x as usize,
+            self.dst_dimensions.y as usize,
+        )
+    }
+
+    /// Checks that `src` and `dst` have the sizes this transform was built for
+    /// and are not empty, which the GPU paths can not handle.
+    pub fn check_sizes(&self, src: Size, dst: Size) -> Result<(), WError> {
+        if src != self.src_size() {
+            return Err(WError::SizeMismatch {
+                expected: self.src_size(),
+                got: src,
+            });
+        }
+        if dst != self.dst_size() {
+            return Err(WError::SizeMismatch {
+                expected: self.dst_size(),
+                got: dst,
+            });
+        }
+        for size in [src, dst] {
+            if size.x == 0 || size.y == 0 {
+                return Err(WError::EmptyImage(size));
+            }
+        }
+        Ok(())
+    }
+}
+
+wtest!(ImageTransform, 256);
+
+/// Goes through `to_vec4` like every other path, so channels the format does
+/// not carry come out zeroed as on the GPU.
+fn sample_nearest<P: WPixel>(src: &Image<P>, pt: (f32, f32), border: BorderMode) -> Option<P> {
+    let pix = border.fetch(src, pt.0.floor() as i32, pt.1.floor() as i32)?;
+    Some(P::from_vec4(pix.to_vec4()))
+}
+
+fn sample_bilinear<P: WPixel>(src: &Image<P>, pt: (f32, f32), border: BorderMode) -> Option<P> {
+    let p0 = (pt.0.floor(), pt.1.floor());
+    let (x0, y0) = (p0.0 as i32, p0.1 as i32);
+    let fpart = (pt.0 - p0.0, pt.1 - p0.1);
+
+    let c0 = border.fetch(src, x0, y0)?.to_vec4();
+    let c1 = border.fetch(src, x0 + 1, y0)?.to_vec4();
+    let c2 = border.fetch(src, x0, y0 + 1)?.to_vec4();
+    let c3 = border.fetch(src, x0 + 1, y0 + 1)?.to_vec4();
+
+    let w = [
+        (1.0 - fpart.0) * (1.0 - fpart.1),
+        fpart.0 * (1.0 - fpart.1),
+        (1.0 - fpart.0) * fpart.1,
+        fpart.0 * fpart.1,
+    ];
+    let mut v = [0.0f32; 4];
+    for (i, v) in v.iter_mut().enumerate() {
+        *v = w[0] * c0[i] + w[1] * c1[i] + w[2] * c2[i] + w[3] * c3[i];
+    }
+    Some(P::from_vec4(v))
+}
+
+/// Same as `bicubic_weights` in the shaders.
+fn bicubic_weights(x: f32) -> [f32; 4] {
+    const A: f32 = -0.75;
+    let w0 = ((A * (x + 1.0) - 5.0 * A) * (x + 1.0) + 8.0 * A) * (x + 1.0) - 4.0 * A;
+    let w1 = ((A + 2.0) * x - (A + 3.0)) * x * x + 1.0;
+    let w2 = ((A + 2.0) * (1.0 - x) - (A + 3.0)) * (1.0 - x) * (1.0 - x) + 1.0;
+    [w0, w1, w2, 1.0 - w0 - w1 - w2]
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        return 1.0;
+    }
+    let x = std::f32::consts::PI * x;
+    x.sin() / x
 }
 
-impl WTestable for ImageTransform {
-    fn wgsl_type() -> WType {
-        WType::Struct("a: vec2<u32>, b: mat3x3<f32>")
+/// Same as `lanczos4_weights` in the shaders.
+fn lanczos4_weights(x: f32) -> [f32; 8] {
+    let mut w = [0.0; 8];
+    let mut sum = 0.0;
+    for (i, w) in w.iter_mut().enumerate() {
+        let d = x + 3.0 - i as f32;
+        *w = sinc(d) * sinc(d / 4.0);
+        sum += *w;
     }
+    w.map(|w| w / sum)
 }
 
-impl WDistribution<ImageTransform> for WStandard {
-    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> ImageTransform {
-        ImageTransform {
-            dimensions: rng.gen(),
-            inverse_matrix: rng.gen(),
+/// Separable N x N kernel centred on the floored position, taps running from
+/// `floor(p) - (N / 2 - 1)` to `floor(p) + N / 2`. Any transparent tap leaves
+/// the destination pixel untouched.
+fn sample_kernel<P: WPixel, const N: usize>(
+    src: &Image<P>,
+    pt: (f32, f32),
+    border: BorderMode,
+    weights: fn(f32) -> [f32; N],
+) -> Option<P> {
+    let p0 = (pt.0.floor(), pt.1.floor());
+    let first = N as i32 / 2 - 1;
+    let (x0, y0) = (p0.0 as i32 - first, p0.1 as i32 - first);
+    let (wx, wy) = (weights(pt.0 - p0.0), weights(pt.1 - p0.1));
+
+    let mut v = [0.0f32; 4];
+    for (j, wy) in wy.iter().enumerate() {
+        let mut row = [0.0f32; 4];
+        for (i, wx) in wx.iter().enumerate() {
+            let c = border.fetch(src, x0 + i as i32, y0 + j as i32)?.to_vec4();
+            for k in 0..4 {
+                row[k] += wx * c[k];
+            }
+        }
+        for k in 0..4 {
+            v[k] += wy * row[k];
         }
     }
+    Some(P::from_vec4(v))
 }
 
-wtest!(ImageTransform, 256);
+/// Caps the footprint of [`Interpolation::Area`] to `2 * 32` source pixels per
+/// axis so a degenerate transform cannot stall the GPU.
+const MAX_AREA_HALF_EXTENT: f32 = 32.0;
+
+/// Overlap of source pixel `[i, i + 1)` with `[lo, hi]`.
+fn area_weight(i: i32, lo: f32, hi: f32) -> f32 {
+    (hi.min((i + 1) as f32) - lo.max(i as f32)).max(0.0)
+}
+
+/// Same as `interpolation_area` in the shaders. The destination pixel square
+/// around `(x + 0.5, y + 0.5)` is mapped through the homography's Jacobian to
+/// an axis aligned source box, and the source pixels are averaged weighted by
+/// how much of them the box covers.
+fn sample_area<P: WPixel>(
+    src: &Image<P>,
+    m: &[[f32; 4]; 3],
+    (x, y): (usize, usize),
+    border: BorderMode,
+    affine: bool,
+) -> Option<P> {
+    let h = map_pos(m, x as f32 + 0.5, y as f32 + 0.5);
+    // Jacobian of the homography at the pixel centre; constant for affine
+    // transforms, which ignore the projective row
+    let (c, dx, dy) = match affine {
+        true => ((h[0], h[1]), (m[0][0], m[0][1]), (m[1][0], m[1][1])),
+        false => {
+            let c = (h[0] / h[2], h[1] / h[2]);
+            let dx = (
+                (m[0][0] - c.0 * m[0][2]) / h[2],
+                (m[0][1] - c.1 * m[0][2]) / h[2],
+            );
+            let dy = (
+                (m[1][0] - c.0 * m[1][2]) / h[2],
+                (m[1][1] - c.1 * m[1][2]) / h[2],
+            );
+            (c, dx, dy)
+        }
+    };
+    let ext = (
+        (0.5 * (dx.0.abs() + dy.0.abs())).clamp(0.5, MAX_AREA_HALF_EXTENT),
+        (0.5 * (dx.1.abs() + dy.1.abs())).clamp(0.5, MAX_AREA_HALF_EXTENT),
+    );
+    let lo = (c.0 - ext.0, c.1 - ext.1);
+    let hi = (c.0 + ext.0, c.1 + ext.1);
+    let first = (lo.0.floor() as i32, lo.1.floor() as i32);
+    let n = (
+        (hi.0.ceil() as i32).saturating_sub(first.0).clamp(0, 66),
+        (hi.1.ceil() as i32).saturating_sub(first.1).clamp(0, 66),
+    );
+
+    let mut v = [0.0f32; 4];
+    let mut wsum = 0.0;
+    for j in first.1..first.1 + n.1 {
+        let wy = area_weight(j, lo.1, hi.1);
+        for i in first.0..first.0 + n.0 {
+            let w = wy * area_weight(i, lo.0, hi.0);
+            let c = border.fetch(src, i, j)?.to_vec4();
+            for k in 0..4 {
+                v[k] += w * c[k];
+            }
+            wsum += w;
+        }
+    }
+    // Degenerate footprint, e.g. a point at infinity
+    if wsum <= 0.0 {
+        return match border {
+            BorderMode::Transparent => None,
+            BorderMode::Constant(value) => Some(P::from_vec4(value)),
+            _ => Some(P::default()),
+        };
+    }
+    Some(P::from_vec4(v.map(|v| v / wsum)))
+}
+
+/// Homogeneous `m * (x, y, 1)`, in the same order as the shaders.
+fn map_pos(m: &[[f32; 4]; 3], x: f32, y: f32) -> [f32; 3] {
+    [
+        m[0][0] * x + m[1][0] * y + m[2][0],
+        m[0][1] * x + m[1][1] * y + m[2][1],
+        m[0][2] * x + m[1][2] * y + m[2][2],
+    ]
+}
 
-pub fn warp_perspective_cpu(
+fn warp_cpu<P: WPixel>(
     transform: &ImageTransform,
     interp: Interpolation,
-    src: &Image,
-    dst: &mut Image,
+    src: &Image<P>,
+    dst: &mut Image<P>,
+    affine: bool,
 ) {
+    let border = transform.border_mode();
+    let m = transform.inverse_matrix.matrix();
     for y in 0..dst.size.y {
         for x in 0..dst.size.x {
-            let pi = (x as f32, y as f32, 1.0);
-            let m = &transform.inverse_matrix.matrix();
-            let from_pos = [
-                m[0][0] * pi.0 + m[1][0] * pi.1 + m[2][0] * pi.2,
-                m[0][1] * pi.0 + m[1][1] * pi.1 + m[2][1] * pi.2,
-                m[0][2] * pi.0 + m[1][2] * pi.1 + m[2][2] * pi.2,
-            ];
-            let pt = (from_pos[0] / from_pos[2], from_pos[1] / from_pos[2]);
-            let pn = (pt.0 as usize, pt.1 as usize);
-            match interp {
-                Interpolation::None => {
-                    if pn.0 < src.size.x && pn.1 < src.size.y {
-                        let pix = src.get(pn.0, pn.1);
-                        *dst.get_mut(x, y) = *pix;
-                    } else {
-                        *dst.get_mut(x, y) = Pix::default();
-                    }
-                }
-                Interpolation::Bilinear => {
-                    if pn.0 <= 0 || pn.0 >= dst.size.x - 1 || pn.1 <= 0 || pn.1 >= dst.size.y - 1 {
-                        *dst.get_mut(x, y) = Pix::new(0, 0, 0, 0);
-                        continue;
-                    }
-                    let pd = (pt.0 - pn.0 as f32, pt.1 - pn.1 as f32);
-                    let v0 = src
-                        .get(pn.0, pn.1)
-                        .mult((1 as f32 - pd.0) * (1.0f32 - pd.1));
-                    let v1 = src.get(pn.0 + 1, pn.1).mult(pd.0 * (1 as f32 - pd.1));
-                    let v2 = src.get(pn.0, pn.1 + 1).mult((1 as f32 - pd.0) * pd.1);
-                    let v3 = src.get(pn.0 + 1, pn.1 + 1).mult(pd.0 * pd.1);
-                    *dst.get_mut(x, y) = v0.add(&v1).add(&v2).add(&v3);
+            let pt = match affine {
+                true => (
+                    m[0][0] * x as f32 + m[1][0] * y as f32 + m[2][0],
+                    m[0][1] * x as f32 + m[1][1] * y as f32 + m[2][1],
+                ),
+                false => {
+                    let from_pos = map_pos(m, x as f32, y as f32);
+                    (from_pos[0] / from_pos[2], from_pos[1] / from_pos[2])
                 }
+            };
+            let pix = match interp {
+                Interpolation::None => sample_nearest(src, pt, border),
+                Interpolation::Bilinear => sample_bilinear(src, pt, border),
+                Interpolation::Bicubic => sample_kernel(src, pt, border, bicubic_weights),
+                Interpolation::Lanczos4 => sample_kernel(src, pt, border, lanczos4_weights),
+                Interpolation::Area => sample_area(src, m, (x, y), border, affine),
+            };
+            if let Some(pix) = pix {
+                *dst.get_mut(x, y) = pix;
             }
         }
     }
 }
 
-pub async fn warp_perspective_gpu(
-    state: &mut WState,
+/// Reference implementation of the GPU warp. Pixels mapped outside `src` are
+/// resolved with the transform's [`BorderMode`].
+pub fn warp_perspective_cpu<P: WPixel>(
     transform: &ImageTransform,
     interp: Interpolation,
-    src: &Image,
-    dst: &mut Image,
+    src: &Image<P>,
+    dst: &mut Image<P>,
 ) {
-    let entry_point = match interp {
-        Interpolation::None => "interpolation_none",
-        Interpolation::Bilinear => "interpolation_bilinear",
-    };
+    warp_cpu(transform, interp, src, dst, false);
+}
 
-    let features = state.device.features();
-    let query_set = if features.contains(wgpu::Features::TIMESTAMP_QUERY) {
-        Some(state.device.create_query_set(&wgpu::QuerySetDescriptor {
-            count: 2,
-            ty: wgpu::QueryType::Timestamp,
-            label: None,
-        }))
-    } else {
-        None
-    };
+/// [`warp_perspective_cpu`] for affine transforms. The projective row of
+/// `transform.inverse_matrix` is ignored, so there is no per-pixel divide.
+pub fn warp_affine_cpu<P: WPixel>(
+    transform: &ImageTransform,
+    interp: Interpolation,
+    src: &Image<P>,
+    dst: &mut Image<P>,
+) {
+    warp_cpu(transform, interp, src, dst, true);
+}
 
-    let cs_module = state
-        .device
-        .create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Image transform shader"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("warp_perspective.wgsl").into()),
-        });
+/// Where the time of one GPU warp went.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct WarpStats {
+    /// Creating buffers for a new image size; zero when they were cached
+    pub buffers: Duration,
+    /// Queueing the transform and image uploads
+    pub upload: Duration,
+    /// Encoding and submitting the compute pass
+    pub dispatch: Duration,
+    /// Time the compute pass took on the GPU, from timestamp queries. `None`
+    /// when the device lacks `TIMESTAMP_QUERY`.
+    pub kernel: Option<Duration>,
+    /// Waiting for the GPU and reading the result back
+    pub download: Duration,
+}
 
-    // Make relevant bytes
-    let src_bytes: &[u8] = bytemuck::cast_slice(&src.data);
-    let transform_bytes = bytemuck::bytes_of(transform);
-
-    // Make relevant buffers
-    let src_buf = state
-        .device
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Input image buffer"),
-            contents: src_bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-        });
-    let transform_buf = state
-        .device
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Transform buffer"),
-            contents: transform_bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-        });
-    assert_eq!(src.size, dst.size);
-    let dst_buf = state.device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Output image buffer"),
-        size: src_bytes.len() as u64,
-        usage: wgpu::BufferUsages::STORAGE
-            | wgpu::BufferUsages::COPY_SRC
-            | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
-    let out_buf = state.device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Output image buffer"),
-        size: src_bytes.len() as u64,
-        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
-    let query_buf = state
-        .device
-        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Query buffer"),
-            contents: &[0; 16],
-            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
-        });
+impl WarpStats {
+    /// Host side wall time of the whole warp.
+    pub fn total(&self) -> Duration {
+        self.buffers + self.upload + self.dispatch + self.download
+    }
 
-    // Bind group layout
-    let bind_group_layout =
-        state
-            .device
-            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            // TODO: Change to uniform?
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: true },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::COMPUTE,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Storage { read_only: false },
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                ],
-            });
+    /// Kernel duration from the milliseconds a [`WProfiler`] reports.
+    pub(crate) fn kernel_time(timings: &HashMap<String, f64>, scope: &str) -> Option<Duration> {
+        timings
+            .get(scope)
+            .map(|ms| Duration::from_secs_f64(ms * 1e-3))
+    }
+}
 
-    // Pipeline
-    let compute_pipeline_layout =
-        state
-            .device
-            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Computer Pipeline"),
-                bind_group_layouts: &[&bind_group_layout],
-                push_constant_ranges: &[],
-            });
-    let pipeline = state
-        .device
-        .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("Main pipeline"),
-            layout: Some(&compute_pipeline_layout),
-            module: &cs_module,
-            entry_point,
+/// Full source of a buffer warp shader: the `ImageTransform` declaration, the
+/// pixel format helpers, `warp_common.wgsl` and `body` with the tile size of
+/// `state` applied.
+pub(crate) fn warp_shader<P: WPixel>(state: &WState, body: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        ImageTransform::wgsl_struct(WAddressSpace::Storage),
+        P::WGSL,
+        include_str!("warp_common.wgsl"),
+        state.tile_size.apply(body)
+    )
+}
+
+/// Compute pipelines of one warp shader, one per [`Interpolation`] and per
+/// perspective/affine variant. Each is compiled on first use, so an engine
+/// only pays for the variants it runs.
+pub(crate) struct WarpPipelines {
+    label: &'static str,
+    shader: String,
+    layout: wgpu::PipelineLayout,
+    /// Perspective and affine shader modules
+    modules: [OnceLock<wgpu::ShaderModule>; 2],
+    /// Indexed by interpolation, then by the affine flag
+    pipelines: [[OnceLock<wgpu::ComputePipeline>; 2]; Interpolation::ALL.len()],
+}
+
+impl WarpPipelines {
+    pub(crate) fn new(label: &'static str, shader: String, layout: wgpu::PipelineLayout) -> Self {
+        Self {
+            label,
+            shader,
+            layout,
+            modules: Default::default(),
+            pipelines: Default::default(),
+        }
+    }
+
+    /// The pipeline for `interp`, compiling it (and its shader module) if
+    /// this is its first use.
+    pub(crate) async fn get(
+        &self,
+        state: &WState,
+        interp: Interpolation,
+        affine: bool,
+    ) -> Result<&wgpu::ComputePipeline, WError> {
+        // `Interpolation::ALL` lists the variants in declaration order
+        let slot = &self.pipelines[interp as usize][affine as usize];
+        if let Some(pipeline) = slot.get() {
+            return Ok(pipeline);
+        }
+        let pipeline = state
+            .validate(|device| {
+                let module = self.modules[affine as usize].get_or_init(|| {
+                    let shader = if affine {
+                        wstring_replace!(
+                            self.shader,
+                            [("const AFFINE: bool = false;", "const AFFINE: bool = true;")]
+                        )
+                    } else {
+                        self.shader.clone()
+                    };
+                    wgpu_shader_load!(self.label, device, shader)
+                });
+                wgpu_compute_pipeline!(
+                    self.label,
+                    device,
+                    &self.layout,
+                    module,
+                    interp.entry_point()
+                )
+            })
+            .await?;
+        Ok(slot.get_or_init(|| pipeline))
+    }
+}
+
+/// Buffers for one (source, destination) pixel count pair, counted in the
+/// padded GPU layout. The bind group is built once against them and reused on
+/// every warp of those sizes.
+struct WarpBuffers<'a, P: WPixel> {
+    src: WBuffer<'a, P>,
+    dst: WBuffer<'a, P>,
+    bind_group: wgpu::BindGroup,
+}
+
+impl<'a, P: WPixel> WarpBuffers<'a, P> {
+    fn new(
+        state: &'a WState,
+        layout: &wgpu::BindGroupLayout,
+        transform_buf: &WBuffer<'a, ImageTransform>,
+        (src_len, dst_len): (usize, usize),
+    ) -> Self {
+        let storage = wgpu::BufferUsages::STORAGE;
+        let src = WBuffer::new(state, "Input image buffer", src_len, storage);
+        let dst = WBuffer::new(state, "Output image buffer", dst_len, storage);
+        let bind_group = wgpu_bind_group!(
+            state.device,
+            layout,
+            [
+                (0, transform_buf.as_binding()),
+                (1, src.as_binding()),
+                (2, dst.as_binding())
+            ]
+        );
+        Self {
+            src,
+            dst,
+            bind_group,
+        }
+    }
+}
+
+/// Reusable GPU perspective warp.
+///
+/// The layouts are created in [`WarpPerspective::new`]; the compute pipeline
+/// for each [`Interpolation`] (and perspective/affine variant) is compiled the
+/// first time it is used and kept. Storage and staging buffers
+/// are created lazily per source/destination byte size and kept around, so
+/// warping a stream of equally sized frames only pays for the upload, the
+/// dispatch and the readback.
+///
+/// An engine warps images of one pixel format `P`; the format's
+/// [`WPixel::WGSL`] is compiled into its shaders.
+pub struct WarpPerspective<'a, P: WPixel = Pix> {
+    state: &'a WState,
+    pub interp: Interpolation,
+    /// When set, every warp records its CPU and GPU spans here
+    pub trace: Option<WTrace>,
+
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipelines: WarpPipelines,
+
+    transform_buf: WBuffer<'a, ImageTransform>,
+    profiler: WProfiler<'a>,
+
+    buffers: HashMap<(usize, usize), WarpBuffers<'a, P>>,
+}
+
+impl<'a, P: WPixel> WarpPerspective<'a, P> {
+    pub async fn new(state: &'a WState, interp: Interpolation) -> Result<Self, WError> {
+        let shader = warp_shader::<P>(state, include_str!("warp_perspective.wgsl"));
+        let (bind_group_layout, compute_pipeline_layout) = state
+            .validate(|device| {
+                // TODO: Change transform to uniform?
+                let bind_group_layout = wgpu_bind_group_layout_compute!(
+                    "Warp perspective layout",
+                    device,
+                    [(0, true), (1, true), (2, false)]
+                );
+                let compute_pipeline_layout =
+                    wgpu_compute_pipeline_layout!(device, &[&bind_group_layout]);
+                (bind_group_layout, compute_pipeline_layout)
+            })
+            .await?;
+        let pipelines =
+            WarpPipelines::new("Warp perspective pipeline", shader, compute_pipeline_layout);
+
+        let transform_buf = WBuffer::new(state, "Transform buffer", 1, wgpu::BufferUsages::STORAGE);
+
+        let profiler = WProfiler::new(state, 1);
+
+        Ok(Self {
+            state,
+            interp,
+            trace: None,
+            bind_group_layout,
+            pipelines,
+            transform_buf,
+            profiler,
+            buffers: HashMap::new(),
+        })
+    }
+
+    /// Warps `src` into `dst` and reports where the time went.
+    pub async fn warp(
+        &mut self,
+        transform: &ImageTransform,
+        src: &Image<P>,
+        dst: &mut Image<P>,
+    ) -> Result<WarpStats, WError> {
+        self.dispatch(transform, src, dst, false).await
+    }
+
+    /// GPU counterpart of [`warp_affine_cpu`]; runs the shader variant
+    /// without the perspective divide.
+    pub async fn warp_affine(
+        &mut self,
+        transform: &ImageTransform,
+        src: &Image<P>,
+        dst: &mut Image<P>,
+    ) -> Result<WarpStats, WError> {
+        self.dispatch(transform, src, dst, true).await
+    }
+
+    async fn dispatch(
+        &mut self,
+        transform: &ImageTransform,
+        src: &Image<P>,
+        dst: &mut Image<P>,
+        affine: bool,
+    ) -> Result<WarpStats, WError> {
+        transform.check_sizes(src.size, dst.size)?;
+        let pipeline = self.pipelines.get(self.state, self.interp, affine).await?;
+
+        let size = (src.gpu_len(), dst.gpu_len());
+        let state = self.state;
+
+        let started = Instant::now();
+        let buffers: &WarpBuffers<P> = self.buffers.entry(size).or_insert_with(|| {
+            WarpBuffers::new(state, &self.bind_group_layout, &self.transform_buf, size)
         });
+        let created = started.elapsed();
 
-    // Bind group
-    let bind_group = state.device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: None,
-        layout: &bind_group_layout,
-        entries: &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: transform_buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: src_buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: dst_buf.as_entire_binding(),
-            },
-        ],
-    });
-
-    let start = std::time::Instant::now();
-
-    // Encoder
-    let mut encoder = state.device.create_command_encoder(&Default::default());
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 0);
-    }
-    {
-        let mut cpass = encoder.begin_compute_pass(&Default::default());
-        cpass.set_pipeline(&pipeline);
-        cpass.set_bind_group(0, &bind_group, &[]);
-        cpass.dispatch_workgroups(dst.size.x as u32, dst.size.y as u32, 1);
-    }
-    if let Some(query_set) = &query_set {
-        encoder.write_timestamp(query_set, 1);
-    }
-
-    // Get data out of device
-    encoder.copy_buffer_to_buffer(&dst_buf, 0, &out_buf, 0, src_bytes.len() as u64);
-    if let Some(query_set) = &query_set {
-        encoder.resolve_query_set(query_set, 0..2, &query_buf, 0);
-    }
-    state.queue.submit(Some(encoder.finish()));
-
-    let out_slice = out_buf.slice(..);
-    let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-    out_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-
-    // More queries
-    let query_slice = query_buf.slice(..);
-    let _query_future = query_slice.map_async(wgpu::MapMode::Read, |_| ());
-    println!("pre-poll {:?}", std::time::Instant::now());
-    state.device.poll(wgpu::Maintain::Wait);
-
-    println!("post-poll {:?}", std::time::Instant::now());
-    if let Some(Ok(())) = receiver.receive().await {
-        let data_raw = &*out_slice.get_mapped_range();
-        let data: &[Pix] = bytemuck::cast_slice(data_raw);
-        println!("{:?}", data.len());
-        dst.data = data.into();
-    }
-    if features.contains(wgpu::Features::TIMESTAMP_QUERY) {
-        let ts_period = state.queue.get_timestamp_period();
-        let ts_data_raw = &*query_slice.get_mapped_range();
-        let ts_data: &[u64] = bytemuck::cast_slice(ts_data_raw);
-        println!(
-            "compute shader elapsed: {:?}ms",
-            (ts_data[1] - ts_data[0]) as f64 * ts_period as f64 * 1e-6
+        let start = Instant::now();
+        self.transform_buf.upload(std::slice::from_ref(transform))?;
+        buffers.src.upload(&src.gpu_data())?;
+        // Pixels the shader skips must not leak in from the previous frame;
+        // with a transparent border they keep what `dst` already holds
+        let transparent = transform.border_mode() == BorderMode::Transparent;
+        if transparent {
+            buffers.dst.upload(&dst.gpu_data())?;
+        }
+        let upload = start.elapsed();
+
+        // Encoder
+        let start = Instant::now();
+        let mut encoder = state.device.create_command_encoder(&Default::default());
+        if !transparent {
+            encoder.clear_buffer(buffers.dst.buffer(), 0, None);
+        }
+        {
+            let mut encoder = self.profiler.scope("warp", &mut encoder);
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, &buffers.bind_group, &[]);
+            let (x, y) = state.tile_size.workgroups(P::dispatch_size(dst.size));
+            cpass.dispatch_workgroups(x, y, 1);
+        }
+        self.profiler.resolve(&mut encoder);
+        state.queue.submit(Some(encoder.finish()));
+        let dispatch = start.elapsed();
+
+        // Get data out of device
+        let start = Instant::now();
+        let (data, timings) =
+            futures::join!(state.read_buffer(&buffers.dst), self.profiler.finish());
+        dst.set_gpu_data(data?);
+        let download = start.elapsed();
+        let kernel = WarpStats::kernel_time(&timings?, "warp");
+
+        let stats = WarpStats {
+            buffers: created,
+            upload,
+            dispatch,
+            kernel,
+            download,
+        };
+        log::debug!("warp {}x{}: {:?}", dst.size.x, dst.size.y, stats);
+        if let Some(trace) = &self.trace {
+            trace.record_warp("warp_perspective", started, &stats);
+        }
+        Ok(stats)
+    }
+}
+
+/// One-shot convenience wrapper around [`WarpPerspective`]. Compiles the
+/// shader on every call; keep a [`WarpPerspective`] around when warping
+/// more than a single image.
+pub async fn warp_perspective_gpu<P: WPixel>(
+    state: &WState,
+    transform: &ImageTransform,
+    interp: Interpolation,
+    src: &Image<P>,
+    dst: &mut Image<P>,
+) -> Result<WarpStats, WError> {
+    WarpPerspective::new(state, interp)
+        .await?
+        .warp(transform, src, dst)
+        .await
+}
+
+/// One-shot convenience wrapper around [`WarpPerspective::warp_affine`].
+pub async fn warp_affine_gpu<P: WPixel>(
+    state: &WState,
+    transform: &ImageTransform,
+    interp: Interpolation,
+    src: &Image<P>,
+    dst: &mut Image<P>,
+) -> Result<WarpStats, WError> {
+    WarpPerspective::new(state, interp)
+        .await?
+        .warp_affine(transform, src, dst)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{Gray16, Gray8, GrayF32, PointF, RgbF32, Rgba8};
+
+    fn test_image(size: Size) -> Image {
+        let mut im = Image::new(size);
+        for (i, p) in im.data.iter_mut().enumerate() {
+            *p = Pix::new(i as u8, (i * 3) as u8, (i * 7) as u8, 0);
+        }
+        im
+    }
+
+    #[test]
+    fn cpu_identity_crops_to_dst_size() {
+        let src = test_image(Size::new(8, 6));
+        let mut dst = Image::new(Size::new(3, 4));
+        let identity =
+            WMat3x3Affine::from_row_major([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+        let transform = ImageTransform::new(src.size, dst.size, identity);
+
+        warp_perspective_cpu(&transform, Interpolation::None, &src, &mut dst);
+
+        for y in 0..dst.size.y {
+            for x in 0..dst.size.x {
+                assert_eq!(dst.get(x, y), src.get(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn border_interpolate_matches_opencv() {
+        // abcdefgh extended by 3 on either side
+        let extend = |border: BorderMode| -> Vec<Option<i32>> {
+            (-3..11).map(|p| border.interpolate(p, 8)).collect()
+        };
+        let some = |v: &[i32]| v.iter().map(|&p| Some(p)).collect::<Vec<_>>();
+
+        assert_eq!(
+            extend(BorderMode::Replicate),
+            some(&[0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 7, 7, 7])
+        );
+        assert_eq!(
+            extend(BorderMode::Reflect),
+            some(&[2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 7, 6, 5])
+        );
+        assert_eq!(
+            extend(BorderMode::Reflect101),
+            some(&[3, 2, 1, 0, 1, 2, 3, 4, 5, 6, 7, 6, 5, 4])
+        );
+        assert_eq!(
+            extend(BorderMode::Wrap),
+            some(&[5, 6, 7, 0, 1, 2, 3, 4, 5, 6, 7, 0, 1, 2])
         );
+        assert_eq!(BorderMode::Transparent.interpolate(-1, 8), None);
+        for border in [
+            BorderMode::Replicate,
+            BorderMode::Reflect,
+            BorderMode::Reflect101,
+            BorderMode::Wrap,
+        ] {
+            assert_eq!(border.interpolate(0, 0), None);
+        }
+    }
+
+    #[test]
+    fn cpu_border_modes_outside_source() {
+        let src = test_image(Size::new(4, 4));
+        // Shift everything 2 pixels right, so the first two columns sample x < 0
+        let shift =
+            WMat3x3Affine::from_row_major([[1.0, 0.0, -2.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+        let marker = Pix::new(1, 2, 3, 4);
+
+        let warp = |border: BorderMode| {
+            let mut dst = Image::new(src.size);
+            dst.data.fill(marker);
+            let transform = ImageTransform::new(src.size, dst.size, shift).with_border(border);
+            warp_perspective_cpu(&transform, Interpolation::None, &src, &mut dst);
+            dst
+        };
+
+        let fill = Pix::new(9, 9, 9, 0);
+        assert_eq!(*warp(BorderMode::constant(fill)).get(0, 1), fill);
+        assert_eq!(*warp(BorderMode::Replicate).get(0, 1), *src.get(0, 1));
+        assert_eq!(*warp(BorderMode::Reflect).get(0, 1), *src.get(1, 1));
+        assert_eq!(*warp(BorderMode::Wrap).get(0, 1), *src.get(2, 1));
+        assert_eq!(*warp(BorderMode::Transparent).get(1, 1), marker);
+        assert_eq!(*warp(BorderMode::Transparent).get(2, 1), *src.get(0, 1));
     }
 
-    println!("Elapsed: {:?}", start.elapsed());
+    #[test]
+    fn cpu_constant_border_keeps_format_range() {
+        let src = test_image(Size::new(4, 4));
+        let shift =
+            WMat3x3Affine::from_row_major([[1.0, 0.0, -2.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+        let transform = ImageTransform::new(src.size, src.size, shift);
+
+        let disparity = convert(&src, |p| GrayF32(p.r as f32));
+        let mut dst = Image::new(src.size);
+        let invalid = transform.with_border(BorderMode::constant(GrayF32(-1.0)));
+        warp_perspective_cpu(&invalid, Interpolation::Bilinear, &disparity, &mut dst);
+        assert_eq!(*dst.get(0, 1), GrayF32(-1.0));
+
+        let depth = convert(&src, |p| Gray16(p.r as u16));
+        let mut dst = Image::new(src.size);
+        let far = transform.with_border(BorderMode::constant(Gray16(1000)));
+        warp_perspective_cpu(&far, Interpolation::Bilinear, &depth, &mut dst);
+        assert_eq!(*dst.get(0, 1), Gray16(1000));
+        assert_eq!(
+            far.border_mode(),
+            BorderMode::Constant([1000.0, 0.0, 0.0, 0.0])
+        );
+    }
+
+    #[test]
+    fn kernel_weights_are_normalised() {
+        for x in [0.0, 0.25, 0.5, 0.9] {
+            let sum: f32 = bicubic_weights(x).iter().sum();
+            assert!((sum - 1.0).abs() < 1e-5);
+            let sum: f32 = lanczos4_weights(x).iter().sum();
+            assert!((sum - 1.0).abs() < 1e-5);
+        }
+        assert_eq!(bicubic_weights(0.0), [0.0, 1.0, 0.0, 0.0]);
+        let w = lanczos4_weights(0.0);
+        assert!((w[3] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn gpu_matches_cpu_for_kernel_interpolations() {
+        use pollster::FutureExt;
+
+        let state = WState::new().block_on().unwrap();
+        let src = test_image(Size::new(16, 12));
+        let dst_size = Size::new(20, 10);
+        let matrix = WMat3x3Affine::from_row_major([
+            [0.7, 0.1, 0.35],
+            [-0.05, 0.9, 0.2],
+            [0.0005, 0.0, 1.0],
+        ]);
+        let mut engine = WarpPerspective::new(&state, Interpolation::Bilinear)
+            .block_on()
+            .unwrap();
+
+        for interp in [
+            Interpolation::Bilinear,
+            Interpolation::Bicubic,
+            Interpolation::Lanczos4,
+            Interpolation::Area,
+        ] {
+            for border in [
+                BorderMode::constant(Pix::new(10, 20, 30, 0)),
+                BorderMode::Replicate,
+                BorderMode::Reflect101,
+            ] {
+                let transform = ImageTransform::new(src.size, dst_size, matrix).with_border(border);
+                let mut cpu = Image::new(dst_size);
+                warp_perspective_cpu(&transform, interp, &src, &mut cpu);
+
+                let mut gpu = Image::new(dst_size);
+                engine.interp = interp;
+                engine.warp(&transform, &src, &mut gpu).block_on().unwrap();
+
+                for (c, g) in cpu.data.iter().zip(gpu.data.iter()) {
+                    for (c, g) in c.to_vec3().iter().zip(g.to_vec3().iter()) {
+                        assert!(
+                            (c - g).abs() <= 2.0,
+                            "{:?} {:?}: {} vs {}",
+                            interp,
+                            border,
+                            c,
+                            g
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// `src` with every pixel converted by `f`.
+    fn convert<P: WPixel>(src: &Image, f: impl Fn(&Pix) -> P) -> Image<P> {
+        Image {
+            data: src.data.iter().map(f).collect(),
+            size: src.size,
+        }
+    }
+
+    #[test]
+    fn gpu_rejects_empty_images() {
+        use pollster::FutureExt;
+
+        let state = WState::new().block_on().unwrap();
+        let mut engine = WarpPerspective::new(&state, Interpolation::Bilinear)
+            .block_on()
+            .unwrap();
+        let full = Size::new(4, 3);
+        for (src_size, dst_size) in [(Size::new(0, 3), full), (full, Size::new(0, 3))] {
+            let transform = ImageTransform::new(src_size, dst_size, WMat3x3Affine::identity());
+            let src = Image::<Pix>::new(src_size);
+            let mut dst = Image::new(dst_size);
+            assert!(matches!(
+                engine.warp(&transform, &src, &mut dst).block_on(),
+                Err(WError::EmptyImage(size)) if size.x == 0
+            ));
+        }
+    }
+
+    #[test]
+    fn cpu_formats_interpolate_like_rgb8() {
+        let src = test_image(Size::new(9, 7));
+        let gray = convert(&src, |p| Gray8(p.r));
+        let rgba = convert(&src, |p| Rgba8::new(p.r, p.g, p.b, p.r));
+        let float = convert(&src, |p| GrayF32(p.r as f32));
+        let matrix =
+            WMat3x3Affine::from_row_major([[0.8, 0.15, 0.4], [-0.1, 1.1, 0.3], [0.001, 0.0, 1.0]]);
+
+        for interp in Interpolation::ALL {
+            // Alpha follows red in `rgba`, the border included
+            for border in [
+                BorderMode::Constant([10.0, 20.0, 30.0, 10.0]),
+                BorderMode::Reflect101,
+            ] {
+                let transform =
+                    ImageTransform::new(src.size, Size::new(11, 8), matrix).with_border(border);
+                let size = transform.dst_size();
+                let mut rgb_dst = Image::new(size);
+                let mut gray_dst = Image::new(size);
+                let mut rgba_dst = Image::new(size);
+                warp_perspective_cpu(&transform, interp, &src, &mut rgb_dst);
+                warp_perspective_cpu(&transform, interp, &gray, &mut gray_dst);
+                warp_perspective_cpu(&transform, interp, &rgba, &mut rgba_dst);
+                for ((c, g), a) in rgb_dst.data.iter().zip(&gray_dst.data).zip(&rgba_dst.data) {
+                    assert_eq!(c.r, g.0, "{:?} {:?}", interp, border);
+                    assert_eq!((c.r, c.g, c.b), (a.r, a.g, a.b));
+                    assert_eq!(a.a, c.r);
+                }
+
+                let mut float_dst = Image::new(size);
+                warp_perspective_cpu(&transform, interp, &float, &mut float_dst);
+                for (c, f) in rgb_dst.data.iter().zip(&float_dst.data) {
+                    assert_eq!(c.r, f.0.clamp(0.0, 255.0) as u8);
+                }
+            }
+        }
+    }
+
+    fn gpu_matches_cpu<P: WPixel>(state: &WState, src: &Image<P>, constant: P, tolerance: f32) {
+        use pollster::FutureExt;
+
+        let matrix = WMat3x3Affine::from_row_major([
+            [0.9, 0.1, -0.6],
+            [-0.05, 0.95, 0.4],
+            [0.0004, 0.0, 1.0],
+        ]);
+        let mut engine = WarpPerspective::<P>::new(state, Interpolation::None)
+            .block_on()
+            .unwrap();
+        for interp in Interpolation::ALL {
+            for border in [BorderMode::constant(constant), BorderMode::Transparent] {
+                // Odd width, so formats with several pixels per word get padded rows
+                let transform =
+                    ImageTransform::new(src.size, Size::new(13, 9), matrix).with_border(border);
+                let mut cpu = Image::new(transform.dst_size());
+                cpu.data.fill(P::from_vec4([7.0; 4]));
+                let mut gpu = cpu.clone();
+                warp_perspective_cpu(&transform, interp, src, &mut cpu);
+                engine.interp = interp;
+                engine.warp(&transform, src, &mut gpu).block_on().unwrap();
+
+                for (c, g) in cpu.data.iter().zip(gpu.data.iter()) {
+                    for (c, g) in c.to_vec4().iter().zip(g.to_vec4().iter()) {
+                        assert!(
+                            (c - g).abs() <= tolerance,
+                            "{:?} {:?}: {} vs {}",
+                            interp,
+                            border,
+                            c,
+                            g
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gpu_matches_cpu_for_pixel_formats() {
+        use pollster::FutureExt;
+
+        let state = WState::new().block_on().unwrap();
+        let src = test_image(Size::new(15, 11));
+        gpu_matches_cpu(&state, &convert(&src, |p| Gray8(p.g)), Gray8(200), 2.0);
+        // A depth and a disparity map with their own invalid values
+        gpu_matches_cpu(
+            &state,
+            &convert(&src, |p| Gray16(p.g as u16 * 251)),
+            Gray16(1000),
+            2.0 * 257.0,
+        );
+        gpu_matches_cpu(
+            &state,
+            &convert(&src, |p| Rgba8::new(p.r, p.g, p.b, p.g)),
+            Rgba8::new(200, 20, 30, 255),
+            2.0,
+        );
+        gpu_matches_cpu(
+            &state,
+            &convert(&src, |p| GrayF32(p.b as f32 / 255.0)),
+            GrayF32(-1.0),
+            1e-2,
+        );
+        gpu_matches_cpu(
+            &state,
+            &convert(&src, |p| RgbF32::new(p.r as f32, p.g as f32, -(p.b as f32))),
+            RgbF32::new(1000.0, -1.0, 0.5),
+            2.0,
+        );
+    }
+
+    #[test]
+    fn cpu_area_averages_downscaled_blocks() {
+        let src = test_image(Size::new(8, 8));
+        let identity =
+            WMat3x3Affine::from_row_major([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+        let mut same = Image::new(src.size);
+        let transform = ImageTransform::new(src.size, src.size, identity);
+        warp_perspective_cpu(&transform, Interpolation::Area, &src, &mut same);
+        for (a, b) in same.data.iter().zip(src.data.iter()) {
+            assert_eq!(a.to_vec3(), b.to_vec3());
+        }
+
+        // Halving: every destination pixel covers a 2x2 source block
+        let half =
+            WMat3x3Affine::from_row_major([[2.0, 0.0, 0.0], [0.0, 2.0, 0.0], [0.0, 0.0, 1.0]]);
+        let mut dst = Image::new(Size::new(4, 4));
+        let transform = ImageTransform::new(src.size, dst.size, half);
+        warp_perspective_cpu(&transform, Interpolation::Area, &src, &mut dst);
+        for y in 0..4 {
+            for x in 0..4 {
+                let mut expected = [0.0f32; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let c = src.get(2 * x + dx, 2 * y + dy).to_vec3();
+                    for k in 0..3 {
+                        expected[k] += c[k] / 4.0;
+                    }
+                }
+                assert_eq!(*dst.get(x, y), Pix::from_vec3(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn cpu_affine_matches_perspective() {
+        let src = test_image(Size::new(12, 10));
+        // 90 degrees about the centre maps (cx + 1, cy) to (cx, cy - 1)
+        let center = PointF::new(6.0, 5.0);
+        let rot = WMat3x3Affine::rotate(center, 90.0);
+        let x = rot.get(0, 0) * 7.0 + rot.get(1, 0) * 5.0 + rot.get(2, 0);
+        let y = rot.get(0, 1) * 7.0 + rot.get(1, 1) * 5.0 + rot.get(2, 1);
+        assert!((x - 6.0).abs() < 1e-5 && (y - 4.0).abs() < 1e-5);
+
+        let transform = ImageTransform::from_forward(src.size, Size::new(9, 11), rot)
+            .unwrap()
+            .with_border(BorderMode::Reflect101);
+        for interp in Interpolation::ALL {
+            let mut expected = Image::new(transform.dst_size());
+            let mut got = Image::new(transform.dst_size());
+            warp_perspective_cpu(&transform, interp, &src, &mut expected);
+            warp_affine_cpu(&transform, interp, &src, &mut got);
+            for (a, b) in got.data.iter().zip(expected.data.iter()) {
+                for (ca, cb) in a.to_vec3().iter().zip(b.to_vec3()) {
+                    assert!((ca - cb).abs() <= 1.0, "{:?}: {} vs {}", interp, a, b);
+                }
+            }
+        }
+    }
+
+    /// Same matrix once with a projective row, which the affine warps have to
+    /// ignore, and once without.
+    fn projective_row_pair(src: Size, dst: Size) -> (ImageTransform, ImageTransform) {
+        let affine = [[0.45, 0.1, 1.5], [-0.05, 0.5, 0.5], [0.0, 0.0, 1.0]];
+        let mut projective = affine;
+        projective[2] = [0.02, 0.01, 1.3];
+        let transform = |m| {
+            ImageTransform::new(src, dst, WMat3x3Affine::from_row_major(m))
+                .with_border(BorderMode::Reflect101)
+        };
+        (transform(projective), transform(affine))
+    }
+
+    #[test]
+    fn cpu_affine_ignores_projective_row() {
+        let src = test_image(Size::new(12, 10));
+        let (projective, affine) = projective_row_pair(src.size, Size::new(20, 16));
+        for interp in Interpolation::ALL {
+            let mut expected = Image::new(affine.dst_size());
+            let mut got = Image::new(affine.dst_size());
+            warp_perspective_cpu(&affine, interp, &src, &mut expected);
+            warp_affine_cpu(&projective, interp, &src, &mut got);
+            assert_eq!(got.data, expected.data, "{:?}", interp);
+        }
+    }
+
+    #[test]
+    fn gpu_affine_ignores_projective_row() {
+        use pollster::FutureExt;
+
+        let state = WState::new().block_on().unwrap();
+        let src = test_image(Size::new(12, 10));
+        let (projective, affine) = projective_row_pair(src.size, Size::new(20, 16));
+        let mut engine = WarpPerspective::new(&state, Interpolation::None)
+            .block_on()
+            .unwrap();
+        for interp in Interpolation::ALL {
+            let mut cpu = Image::new(affine.dst_size());
+            warp_perspective_cpu(&affine, interp, &src, &mut cpu);
+            let mut gpu = Image::new(affine.dst_size());
+            engine.interp = interp;
+            engine
+                .warp_affine(&projective, &src, &mut gpu)
+                .block_on()
+                .unwrap();
+            for (c, g) in cpu.data.iter().zip(gpu.data.iter()) {
+                for (c, g) in c.to_vec3().iter().zip(g.to_vec3().iter()) {
+                    assert!((c - g).abs() <= 2.0, "{:?}: {} vs {}", interp, c, g);
+                }
+            }
+        }
+    }
 }
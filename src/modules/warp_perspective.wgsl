@@ -1,10 +1,21 @@
+// `ImageTransform` is generated from the Rust struct by `WgslStruct` and
+// prepended by the host. `border` is the border mode and `border_value` the
+// constant border pixel. `PIXELS_PER_WORD`, `load_pixel` and `store_pixel`
+// come from the pixel format (`WPixel::WGSL`), also prepended by the host;
+// pixels are interpolated as `vec4<f32>` in the format's scale, which is also
+// the scale of `border_value`.
+// The `BORDER_*` constants, interpolation weights and `border_interpolate`
+// are prepended from `warp_common.wgsl`.
 
-struct ImageTransform {
-    odim: vec2<u32>,
-    tmatrix: mat3x3<f32>,
-}
+// Workgroup tile; the host replaces both with `WState::tile_size` and
+// dispatches ceil(width / (TILE_X * PIXELS_PER_WORD)) x ceil(height / TILE_Y)
+// workgroups
+const TILE_X: u32 = 16u;
+const TILE_Y: u32 = 16u;
 
-type RGBPixel = u32;
+// Replaced with `true` by the host to build the affine variant, which ignores
+// the projective row of `inverse_matrix` and skips the divide
+const AFFINE: bool = false;
 
 // TODO: Change to uniform?
 @group(0)
@@ -13,76 +24,236 @@ var<storage, read> transform: ImageTransform;
 
 @group(0)
 @binding(1)
-var<storage, read> input: array<RGBPixel>;
+var<storage, read> input: array<u32>;
 
 @group(0)
 @binding(2)
-var<storage, read_write> output: array<RGBPixel>;
+var<storage, read_write> output: array<u32>;
 
 fn ind(x: u32, y: u32, width: u32) -> u32 {
     return y * width + x;
 }
 
-fn red(pixel: RGBPixel) -> u32 {
-    return pixel & 0xFFu;
+fn dst_ind(pos: vec2<u32>) -> u32 {
+    return ind(pos.x, pos.y, row_stride(transform.dst_dimensions.x));
 }
 
-fn green(pixel: RGBPixel) -> u32 {
-    return (pixel >> 8u) & 0xFFu;
+fn src_pos(x: i32, y: i32) -> vec2<i32> {
+    let mode = transform.border;
+    return vec2<i32>(border_interpolate(x, i32(transform.src_dimensions.x), mode), border_interpolate(y, i32(transform.src_dimensions.y), mode));
 }
 
-fn blue(pixel: RGBPixel) -> u32 {
-    return (pixel >> 16u) & 0xFFu;
+// Source pixel after border handling. Transparent borders have to be checked
+// by the caller since there is nothing to return for them.
+fn fetch(p: vec2<i32>) -> vec4<f32> {
+    if p.x < 0 || p.y < 0 {
+        return transform.border_value;
+    }
+    return load_pixel(ind(u32(p.x), u32(p.y), row_stride(transform.src_dimensions.x)));
 }
 
-fn vec3f_from_pixel(pixel: RGBPixel) -> vec3<f32> {
-    return vec3<f32>(f32(red(pixel)), f32(green(pixel)), f32(blue(pixel)));
+fn map_pos(dst: vec2<u32>) -> vec2<f32> {
+    if AFFINE {
+        let m = transform.inverse_matrix;
+        return m[0].xy * f32(dst.x) + m[1].xy * f32(dst.y) + m[2].xy;
+    }
+    var pos = vec3<f32>(f32(dst.x), f32(dst.y), 1.0);
+    pos = transform.inverse_matrix * pos;
+    return pos.xy / pos.z;
 }
 
-fn pixel_from_vec3u(v: vec3<u32>) -> RGBPixel {
-    return (u32(v.x)) | (u32(v.y) << 8u) | (u32(v.z) << 16u);
+fn warp_none(pos: vec2<u32>) {
+    let fpos = floor(map_pos(pos));
+    let p = src_pos(i32(fpos.x), i32(fpos.y));
+
+    if is_outside(p) && transform.border == BORDER_TRANSPARENT {
+        return;
+    }
+
+    store_pixel(dst_ind(pos), fetch(p));
 }
 
-@compute
-@workgroup_size(1)
-fn interpolation_none(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    var pos = vec3<f32>(f32(global_id.x), f32(global_id.y), 1.0);
-    pos = transform.tmatrix * pos;
-    pos /= pos.z;
+fn warp_bilinear(pos: vec2<u32>) {
+    // Floating point position
+    let fpos = map_pos(pos);
+    // Floored floating point position
+    let rpos = floor(fpos);
+    //  Floored integer position
+    let ipos = vec2<i32>(i32(rpos.x), i32(rpos.y));
+    // Fractional part
+    let fpart = fpos - rpos;
+
+    let s0 = src_pos(ipos.x, ipos.y);
+    let s1 = src_pos(ipos.x + 1, ipos.y);
+    let s2 = src_pos(ipos.x, ipos.y + 1);
+    let s3 = src_pos(ipos.x + 1, ipos.y + 1);
 
-    if pos.x < 0.0 || pos.x >= f32(transform.odim.x) || pos.y < 0.0 || pos.y >= f32(transform.odim.y) {
+    if transform.border == BORDER_TRANSPARENT && (is_outside(s0) || is_outside(s1) || is_outside(s2) || is_outside(s3)) {
         return;
     }
 
-    let src_ind = ind(u32(pos.x), u32(pos.y), transform.odim.x);
-    let dst_ind = ind(global_id.x, global_id.y, transform.odim.x);
+    let p0 = (1.0 - fpart.x) * (1.0 - fpart.y) * fetch(s0);
+    let p1 = fpart.x * (1.0 - fpart.y) * fetch(s1);
+    let p2 = (1.0 - fpart.x) * fpart.y * fetch(s2);
+    let p3 = fpart.x * fpart.y * fetch(s3);
 
-    output[dst_ind] = input[src_ind];
+    store_pixel(dst_ind(pos), p0 + p1 + p2 + p3);
 }
 
-@compute
-@workgroup_size(1)
-fn interpolation_bilinear(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    // Floating point position
-    var fpos = vec3<f32>(f32(global_id.x), f32(global_id.y), 1.0);
-    fpos = transform.tmatrix * fpos;
-    fpos /= fpos.z;
+fn warp_bicubic(pos: vec2<u32>) {
+    let fpos = map_pos(pos);
+    let rpos = floor(fpos);
+    let ipos = vec2<i32>(i32(rpos.x), i32(rpos.y));
+    let wx = bicubic_weights(fpos.x - rpos.x);
+    let wy = bicubic_weights(fpos.y - rpos.y);
+
+    var sum = vec4<f32>(0.0);
+    for (var j = 0; j < 4; j++) {
+        var row = vec4<f32>(0.0);
+        for (var i = 0; i < 4; i++) {
+            let s = src_pos(ipos.x - 1 + i, ipos.y - 1 + j);
+            if is_outside(s) && transform.border == BORDER_TRANSPARENT {
+                return;
+            }
+            row += wx[i] * fetch(s);
+        }
+        sum += wy[j] * row;
+    }
+
+    store_pixel(dst_ind(pos), sum);
+}
+
+fn warp_lanczos4(pos: vec2<u32>) {
+    let fpos = map_pos(pos);
+    let rpos = floor(fpos);
+    let ipos = vec2<i32>(i32(rpos.x), i32(rpos.y));
+    var wx = lanczos4_weights(fpos.x - rpos.x);
+    var wy = lanczos4_weights(fpos.y - rpos.y);
+
+    var sum = vec4<f32>(0.0);
+    for (var j = 0; j < 8; j++) {
+        var row = vec4<f32>(0.0);
+        for (var i = 0; i < 8; i++) {
+            let s = src_pos(ipos.x - 3 + i, ipos.y - 3 + j);
+            if is_outside(s) && transform.border == BORDER_TRANSPARENT {
+                return;
+            }
+            row += wx[i] * fetch(s);
+        }
+        sum += wy[j] * row;
+    }
+
+    store_pixel(dst_ind(pos), sum);
+}
+
+fn warp_area(pos: vec2<u32>) {
+    let m = transform.inverse_matrix;
+    let h = m * vec3<f32>(f32(pos.x) + 0.5, f32(pos.y) + 0.5, 1.0);
+    // Jacobian of the homography at the pixel centre; constant for the
+    // affine variant, which ignores the projective row
+    var c = h.xy;
+    var dx = m[0].xy;
+    var dy = m[1].xy;
+    if !AFFINE {
+        c = h.xy / h.z;
+        dx = (m[0].xy - c * m[0].z) / h.z;
+        dy = (m[1].xy - c * m[1].z) / h.z;
+    }
+    let ext = clamp(0.5 * (abs(dx) + abs(dy)), vec2<f32>(0.5), vec2<f32>(MAX_AREA_HALF_EXTENT));
+    let lo = c - ext;
+    let hi = c + ext;
+    let first = vec2<i32>(i32(floor(lo.x)), i32(floor(lo.y)));
+    let n = clamp(vec2<i32>(i32(ceil(hi.x)), i32(ceil(hi.y))) - first, vec2<i32>(0), vec2<i32>(66));
+
+    var sum = vec4<f32>(0.0);
+    var wsum = 0.0;
+    for (var j = first.y; j < first.y + n.y; j++) {
+        let wy = area_weight(j, lo.y, hi.y);
+        for (var i = first.x; i < first.x + n.x; i++) {
+            let w = wy * area_weight(i, lo.x, hi.x);
+            let s = src_pos(i, j);
+            if is_outside(s) && transform.border == BORDER_TRANSPARENT {
+                return;
+            }
+            sum += w * fetch(s);
+            wsum += w;
+        }
+    }
 
-    if fpos.x < 0.0 || fpos.x >= f32(transform.odim.x) || fpos.y < 0.0 || fpos.y >= f32(transform.odim.y) {
+    // Degenerate footprint, e.g. a point at infinity
+    if wsum <= 0.0 {
+        if transform.border != BORDER_TRANSPARENT {
+            store_pixel(dst_ind(pos), transform.border_value);
+        }
         return;
     }
+    store_pixel(dst_ind(pos), sum / wsum);
+}
 
-    // Floored floating point position
-    let rpos = vec2<f32>(floor(fpos.x), floor(fpos.y));
-    //  Floored integer position
-    let ipos = vec2<u32>(u32(rpos.x), u32(rpos.y));
-    // Fractional part
-    let fpart = vec2<f32>(fpos.x - rpos.x, fpos.y - rpos.y);
+// Every invocation covers the `PIXELS_PER_WORD` pixels of one output word, so
+// no two invocations write the same word
 
-    let p0 = (1.0 - fpart.x) * (1.0 - fpart.y) * vec3f_from_pixel(input[ind(ipos.x, ipos.y, transform.odim.x)]);
-    let p1 = fpart.x * (1.0 - fpart.y) * vec3f_from_pixel(input[ind(ipos.x + 1u, ipos.y, transform.odim.x)]);
-    let p2 = (1.0 - fpart.x) * fpart.y * vec3f_from_pixel(input[ind(ipos.x, ipos.y + 1u, transform.odim.x)]);
-    let p3 = fpart.x * fpart.y * vec3f_from_pixel(input[ind(ipos.x + 1u, ipos.y + 1u, transform.odim.x)]);
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_none(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The last row and column of tiles overhang the image
+        if pos.x >= transform.dst_dimensions.x || pos.y >= transform.dst_dimensions.y {
+            return;
+        }
+        warp_none(pos);
+    }
+}
 
-    output[ind(global_id.x, global_id.y, transform.odim.x)] = pixel_from_vec3u(vec3<u32>(p0 + p1 + p2 + p3));
-}
\ No newline at end of file
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_bilinear(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The last row and column of tiles overhang the image
+        if pos.x >= transform.dst_dimensions.x || pos.y >= transform.dst_dimensions.y {
+            return;
+        }
+        warp_bilinear(pos);
+    }
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_bicubic(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The last row and column of tiles overhang the image
+        if pos.x >= transform.dst_dimensions.x || pos.y >= transform.dst_dimensions.y {
+            return;
+        }
+        warp_bicubic(pos);
+    }
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_lanczos4(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The last row and column of tiles overhang the image
+        if pos.x >= transform.dst_dimensions.x || pos.y >= transform.dst_dimensions.y {
+            return;
+        }
+        warp_lanczos4(pos);
+    }
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_area(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    for (var lane = 0u; lane < PIXELS_PER_WORD; lane++) {
+        let pos = vec2<u32>(global_id.x * PIXELS_PER_WORD + lane, global_id.y);
+        // The last row and column of tiles overhang the image
+        if pos.x >= transform.dst_dimensions.x || pos.y >= transform.dst_dimensions.y {
+            return;
+        }
+        warp_area(pos);
+    }
+}
@@ -0,0 +1,479 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::{
+    error::WError,
+    image::{Image, Pix, Size},
+    layout::{WAddressSpace, WgslStruct},
+    profiler::WProfiler,
+    setup::{WBuffer, WState},
+    trace::WTrace,
+};
+
+pub use super::warp_perspective::{BorderMode, ImageTransform, Interpolation, WarpStats};
+
+/// Interpolations the sampler can do in hardware.
+pub const SUPPORTED: [Interpolation; 2] = [Interpolation::None, Interpolation::Bilinear];
+
+/// Sampler address mode standing in for `border`. Constant and transparent
+/// borders are handled in the shader, which only needs the sampler to stay
+/// inside the texture. The hardware mirror mode repeats the edge pixel, so
+/// there is no address mode for [`BorderMode::Reflect101`].
+pub fn address_mode(border: BorderMode) -> Result<wgpu::AddressMode, WError> {
+    match border {
+        BorderMode::Constant(_) | BorderMode::Replicate | BorderMode::Transparent => {
+            Ok(wgpu::AddressMode::ClampToEdge)
+        }
+        BorderMode::Reflect => Ok(wgpu::AddressMode::MirrorRepeat),
+        BorderMode::Wrap => Ok(wgpu::AddressMode::Repeat),
+        BorderMode::Reflect101 => Err(WError::UnsupportedBorder(border)),
+    }
+}
+
+fn filter_mode(interp: Interpolation) -> wgpu::FilterMode {
+    match interp {
+        Interpolation::Bilinear => wgpu::FilterMode::Linear,
+        _ => wgpu::FilterMode::Nearest,
+    }
+}
+
+fn extent(size: Size) -> wgpu::Extent3d {
+    wgpu::Extent3d {
+        width: size.x as u32,
+        height: size.y as u32,
+        depth_or_array_layers: 1,
+    }
+}
+
+/// Sampler cache key: the filter comes from the interpolation.
+type SamplerKey = (Interpolation, wgpu::AddressMode);
+
+/// Source and destination textures for one (source, destination) size pair,
+/// plus the buffer the destination is copied into for readback. Its rows are
+/// padded to `wgpu::COPY_BYTES_PER_ROW_ALIGNMENT`.
+struct WarpTextures<'a> {
+    src: wgpu::Texture,
+    dst: wgpu::Texture,
+    src_view: wgpu::TextureView,
+    dst_view: wgpu::TextureView,
+    /// One bind group per sampler used with these textures
+    bind_groups: HashMap<SamplerKey, wgpu::BindGroup>,
+    readback: WBuffer<'a, Pix>,
+    /// Row stride of `readback`, in pixels
+    padded_width: usize,
+}
+
+impl<'a> WarpTextures<'a> {
+    fn new(state: &'a WState, src_size: Size, dst_size: Size) -> Self {
+        let texture = |label, size, usage| {
+            state.device.create_texture(&wgpu::TextureDescriptor {
+                label: Some(label),
+                size: extent(size),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                usage,
+                view_formats: &[],
+            })
+        };
+        let src = texture(
+            "Input image texture",
+            src_size,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        );
+        let dst = texture(
+            "Output image texture",
+            dst_size,
+            wgpu::TextureUsages::STORAGE_BINDING
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::COPY_DST,
+        );
+        let row_bytes = (dst_size.x * std::mem::size_of::<Pix>()) as u32;
+        let padded_width = row_bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) as usize
+            / std::mem::size_of::<Pix>();
+        let readback = WBuffer::new(
+            state,
+            "Output readback buffer",
+            padded_width * dst_size.y,
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        );
+        Self {
+            src_view: src.create_view(&Default::default()),
+            dst_view: dst.create_view(&Default::default()),
+            src,
+            dst,
+            bind_groups: HashMap::new(),
+            readback,
+            padded_width,
+        }
+    }
+}
+
+fn write_texture(state: &WState, texture: &wgpu::Texture, im: &Image) {
+    state.queue.write_texture(
+        texture.as_image_copy(),
+        bytemuck::cast_slice(&im.data),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some((im.size.x * std::mem::size_of::<Pix>()) as u32),
+            rows_per_image: Some(im.size.y as u32),
+        },
+        extent(im.size),
+    );
+}
+
+/// Perspective warp that samples an `Rgba8Unorm` texture with a
+/// `wgpu::Sampler` instead of reading packed pixels from a storage buffer.
+///
+/// Filtering happens in the texture units, so only [`SUPPORTED`]
+/// interpolations are available, and hardware bilinear weights have less
+/// precision than the buffer path. Border modes map to sampler address modes
+/// through [`address_mode`]; the ones it has no mode for are rejected, as are
+/// empty images. Textures, their views and bind groups, samplers and the
+/// pipelines are cached like in
+/// [`WarpPerspective`](super::warp_perspective::WarpPerspective).
+/// Only [`Pix`] images are supported; other [`WPixel`](crate::image::WPixel)
+/// formats go through the buffer path.
+pub struct WarpTexture<'a> {
+    state: &'a WState,
+    pub interp: Interpolation,
+    /// When set, every warp records its CPU and GPU spans here
+    pub trace: Option<WTrace>,
+
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipelines: HashMap<Interpolation, wgpu::ComputePipeline>,
+    samplers: HashMap<SamplerKey, wgpu::Sampler>,
+
+    transform_buf: WBuffer<'a, ImageTransform>,
+    profiler: WProfiler<'a>,
+
+    textures: HashMap<(usize, usize, usize, usize), WarpTextures<'a>>,
+}
+
+impl<'a> WarpTexture<'a> {
+    pub async fn new(state: &'a WState, interp: Interpolation) -> Result<Self, WError> {
+        if !SUPPORTED.contains(&interp) {
+            return Err(WError::UnsupportedInterpolation(interp));
+        }
+        let shader = format!(
+            "{}\n{}",
+            ImageTransform::wgsl_struct(WAddressSpace::Storage),
+            state.tile_size.apply(include_str!("warp_texture.wgsl"))
+        );
+        let (bind_group_layout, pipelines) = state
+            .validate(|device| {
+                let cs_module = wgpu_shader_load!("Texture warp shader", device, &shader);
+
+                let entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+                    binding,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty,
+                    count: None,
+                };
+                let bind_group_layout =
+                    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                        label: Some("Texture warp layout"),
+                        entries: &[
+                            wgpu_bind_gle_compute!(0, true),
+                            entry(
+                                1,
+                                wgpu::BindingType::Texture {
+                                    sample_type: wgpu::TextureSampleType::Float {
+                                        filterable: true,
+                                    },
+                                    view_dimension: wgpu::TextureViewDimension::D2,
+                                    multisampled: false,
+                                },
+                            ),
+                            entry(
+                                2,
+                                wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                            ),
+                            entry(
+                                3,
+                                wgpu::BindingType::StorageTexture {
+                                    access: wgpu::StorageTextureAccess::WriteOnly,
+                                    format: wgpu::TextureFormat::Rgba8Unorm,
+                                    view_dimension: wgpu::TextureViewDimension::D2,
+                                },
+                            ),
+                        ],
+                    });
+                let compute_pipeline_layout =
+                    wgpu_compute_pipeline_layout!(device, &[&bind_group_layout]);
+
+                let pipelines: HashMap<_, _> = SUPPORTED
+                    .iter()
+                    .map(|interp| {
+                        let pipeline = wgpu_compute_pipeline!(
+                            "Texture warp pipeline",
+                            device,
+                            &compute_pipeline_layout,
+                            &cs_module,
+                            interp.entry_point()
+                        );
+                        (*interp, pipeline)
+                    })
+                    .collect();
+                (bind_group_layout, pipelines)
+            })
+            .await?;
+
+        let transform_buf = WBuffer::new(state, "Transform buffer", 1, wgpu::BufferUsages::STORAGE);
+        let profiler = WProfiler::new(state, 1);
+
+        Ok(Self {
+            state,
+            interp,
+            trace: None,
+            bind_group_layout,
+            pipelines,
+            samplers: HashMap::new(),
+            transform_buf,
+            profiler,
+            textures: HashMap::new(),
+        })
+    }
+
+    /// Warps `src` into `dst` and reports where the time went.
+    pub async fn warp(
+        &mut self,
+        transform: &ImageTransform,
+        src: &Image,
+        dst: &mut Image,
+    ) -> Result<WarpStats, WError> {
+        transform.check_sizes(src.size, dst.size)?;
+        let pipeline = self
+            .pipelines
+            .get(&self.interp)
+            .ok_or(WError::UnsupportedInterpolation(self.interp))?;
+
+        let border = transform.border_mode();
+        let address_mode = address_mode(border)?;
+
+        let key = (src.size.x, src.size.y, dst.size.x, dst.size.y);
+        let state = self.state;
+
+        let started = Instant::now();
+        let textures = self
+            .textures
+            .entry(key)
+            .or_insert_with(|| WarpTextures::new(state, src.size, dst.size));
+        let sampler_key = (self.interp, address_mode);
+        let sampler = self.samplers.entry(sampler_key).or_insert_with(|| {
+            let (filter, address_mode) = (filter_mode(sampler_key.0), sampler_key.1);
+            state.device.create_sampler(&wgpu::SamplerDescriptor {
+                label: Some("Input image sampler"),
+                address_mode_u: address_mode,
+                address_mode_v: address_mode,
+                address_mode_w: address_mode,
+                mag_filter: filter,
+                min_filter: filter,
+                ..Default::default()
+            })
+        });
+        let (layout, transform_buf) = (&self.bind_group_layout, &self.transform_buf);
+        let bind_group = textures.bind_groups.entry(sampler_key).or_insert_with(|| {
+            wgpu_bind_group!(
+                state.device,
+                layout,
+                [
+                    (0, transform_buf.as_binding()),
+                    (1, wgpu::BindingResource::TextureView(&textures.src_view)),
+                    (2, wgpu::BindingResource::Sampler(sampler)),
+                    (3, wgpu::BindingResource::TextureView(&textures.dst_view))
+                ]
+            )
+        });
+        let created = started.elapsed();
+
+        let start = Instant::now();
+        self.transform_buf.upload(std::slice::from_ref(transform))?;
+        write_texture(state, &textures.src, src);
+        // Every other border mode writes each output pixel
+        if border == BorderMode::Transparent {
+            write_texture(state, &textures.dst, dst);
+        }
+        let upload = start.elapsed();
+
+        // Encoder
+        let start = Instant::now();
+        let mut encoder = state.device.create_command_encoder(&Default::default());
+        {
+            let mut encoder = self.profiler.scope("warp", &mut encoder);
+            let mut cpass = encoder.begin_compute_pass(&Default::default());
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, bind_group, &[]);
+            let (x, y) = state.tile_size.workgroups(dst.size);
+            cpass.dispatch_workgroups(x, y, 1);
+        }
+        encoder.copy_texture_to_buffer(
+            textures.dst.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: textures.readback.buffer(),
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(
+                        (textures.padded_width * std::mem::size_of::<Pix>()) as u32,
+                    ),
+                    rows_per_image: None,
+                },
+            },
+            extent(dst.size),
+        );
+        self.profiler.resolve(&mut encoder);
+        state.queue.submit(Some(encoder.finish()));
+        let dispatch = start.elapsed();
+
+        // Get data out of device
+        let start = Instant::now();
+        let (data, timings) = futures::join!(
+            state.read_buffer(&textures.readback),
+            self.profiler.finish()
+        );
+        let data = data?;
+        let width = dst.size.x;
+        for (row, padded) in dst
+            .data
+            .chunks_exact_mut(width)
+            .zip(data.chunks_exact(textures.padded_width))
+        {
+            row.copy_from_slice(&padded[..width]);
+        }
+        let download = start.elapsed();
+        let kernel = WarpStats::kernel_time(&timings?, "warp");
+
+        let stats = WarpStats {
+            buffers: created,
+            upload,
+            dispatch,
+            kernel,
+            download,
+        };
+        log::debug!("texture warp {}x{}: {:?}", dst.size.x, dst.size.y, stats);
+        if let Some(trace) = &self.trace {
+            trace.record_warp("warp_texture", started, &stats);
+        }
+        Ok(stats)
+    }
+}
+
+/// One-shot convenience wrapper around [`WarpTexture`].
+pub async fn warp_texture_gpu(
+    state: &WState,
+    transform: &ImageTransform,
+    interp: Interpolation,
+    src: &Image,
+    dst: &mut Image,
+) -> Result<WarpStats, WError> {
+    WarpTexture::new(state, interp)
+        .await?
+        .warp(transform, src, dst)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{modules::warp_perspective::warp_perspective_cpu, types::WMat3x3Affine};
+
+    #[test]
+    fn border_modes_map_to_address_modes() {
+        let mode = |border| address_mode(border).unwrap();
+        assert_eq!(mode(BorderMode::Replicate), wgpu::AddressMode::ClampToEdge);
+        assert_eq!(
+            mode(BorderMode::constant(Pix::default())),
+            wgpu::AddressMode::ClampToEdge
+        );
+        assert_eq!(mode(BorderMode::Reflect), wgpu::AddressMode::MirrorRepeat);
+        assert_eq!(mode(BorderMode::Wrap), wgpu::AddressMode::Repeat);
+        assert!(matches!(
+            address_mode(BorderMode::Reflect101),
+            Err(WError::UnsupportedBorder(BorderMode::Reflect101))
+        ));
+    }
+
+    #[test]
+    fn gpu_nearest_matches_cpu() {
+        use pollster::FutureExt;
+
+        let state = WState::new().block_on().unwrap();
+        let mut src = Image::new(Size::new(70, 20));
+        for (i, p) in src.data.iter_mut().enumerate() {
+            *p = Pix::new(i as u8, (i * 3) as u8, (i * 7) as u8, 255);
+        }
+        let matrix =
+            WMat3x3Affine::from_row_major([[0.9, 0.2, -3.0], [-0.1, 1.1, 2.0], [0.0, 0.0, 1.0]]);
+        // A width that is not a multiple of the 64 pixel row alignment
+        let transform = ImageTransform::new(src.size, Size::new(37, 15), matrix)
+            .with_border(BorderMode::constant(Pix::new(1, 2, 3, 255)));
+
+        let mut cpu = Image::new(transform.dst_size());
+        warp_perspective_cpu(&transform, Interpolation::None, &src, &mut cpu);
+        let mut gpu = Image::new(transform.dst_size());
+        warp_texture_gpu(&state, &transform, Interpolation::None, &src, &mut gpu)
+            .block_on()
+            .unwrap();
+        assert_eq!(cpu.data, gpu.data);
+    }
+
+    #[test]
+    fn gpu_bilinear_matches_cpu() {
+        use pollster::FutureExt;
+
+        let state = WState::new().block_on().unwrap();
+        // Smooth, so the reduced precision of hardware filtering stays small
+        let mut src = Image::new(Size::new(24, 18));
+        for y in 0..src.size.y {
+            for x in 0..src.size.x {
+                *src.get_mut(x, y) = Pix::new((x * 9) as u8, (y * 12) as u8, (x + y) as u8, 0);
+            }
+        }
+        // Reaches past every edge, so each tap straddling the border counts
+        let matrix =
+            WMat3x3Affine::from_row_major([[1.3, 0.1, -4.3], [-0.1, 1.2, -3.6], [0.0, 0.0, 1.0]]);
+        let mut engine = WarpTexture::new(&state, Interpolation::Bilinear)
+            .block_on()
+            .unwrap();
+
+        for border in [
+            BorderMode::constant(Pix::new(200, 100, 50, 0)),
+            BorderMode::Replicate,
+            BorderMode::Reflect,
+            BorderMode::Wrap,
+            BorderMode::Transparent,
+        ] {
+            let transform =
+                ImageTransform::new(src.size, Size::new(30, 25), matrix).with_border(border);
+            let mut cpu = Image::new(transform.dst_size());
+            warp_perspective_cpu(&transform, Interpolation::Bilinear, &src, &mut cpu);
+            let mut gpu = Image::new(transform.dst_size());
+            engine.warp(&transform, &src, &mut gpu).block_on().unwrap();
+            for (c, g) in cpu.data.iter().zip(gpu.data.iter()) {
+                for (c, g) in c.to_vec3().iter().zip(g.to_vec3().iter()) {
+                    assert!((c - g).abs() <= 2.0, "{:?}: {} vs {}", border, c, g);
+                }
+            }
+        }
+
+        let transform = ImageTransform::new(src.size, Size::new(30, 25), matrix)
+            .with_border(BorderMode::Reflect101);
+        let mut gpu = Image::new(transform.dst_size());
+        assert!(engine.warp(&transform, &src, &mut gpu).block_on().is_err());
+
+        // Zero-extent textures can not be created
+        for (src_size, dst_size) in [
+            (Size::new(0, 18), Size::new(30, 25)),
+            (src.size, Size::new(30, 0)),
+        ] {
+            let transform = ImageTransform::new(src_size, dst_size, matrix);
+            let mut gpu = Image::new(dst_size);
+            assert!(matches!(
+                engine
+                    .warp(&transform, &Image::new(src_size), &mut gpu)
+                    .block_on(),
+                Err(WError::EmptyImage(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// `ImageTransform` is generated from the Rust struct by `WgslStruct` and
+// prepended by the host. `border` is the border mode and `border_value` the
+// constant border pixel in `0.0..=255.0`. Every border mode but constant and
+// transparent is handled by the sampler's address mode.
+
+// Must match `BorderMode::to_gpu`
+const BORDER_CONSTANT: u32 = 0u;
+const BORDER_TRANSPARENT: u32 = 5u;
+
+// Workgroup tile; the host replaces both with `WState::tile_size` and
+// dispatches ceil(width / TILE_X) x ceil(height / TILE_Y) workgroups
+const TILE_X: u32 = 16u;
+const TILE_Y: u32 = 16u;
+
+@group(0)
+@binding(0)
+var<storage, read> transform: ImageTransform;
+
+@group(0)
+@binding(1)
+var input: texture_2d<f32>;
+
+@group(0)
+@binding(2)
+var input_sampler: sampler;
+
+@group(0)
+@binding(3)
+var output: texture_storage_2d<rgba8unorm, write>;
+
+// `Pix` carries no alpha, so it is written as zero like in the buffer path
+fn store(dst: vec2<i32>, c: vec4<f32>) {
+    textureStore(output, dst, vec4<f32>(c.rgb, 0.0));
+}
+
+fn map_pos(global_id: vec3<u32>) -> vec2<f32> {
+    var pos = vec3<f32>(f32(global_id.x), f32(global_id.y), 1.0);
+    pos = transform.inverse_matrix * pos;
+    return pos.xy / pos.z;
+}
+
+// Handles the border modes the sampler can not express. Returns false when
+// `p` lies outside the source and the pixel has been dealt with.
+fn inside_or_border(p: vec2<f32>, dst: vec2<i32>) -> bool {
+    let size = vec2<f32>(transform.src_dimensions);
+    let outside = p.x < 0.0 || p.y < 0.0 || p.x >= size.x || p.y >= size.y;
+    if !outside {
+        return true;
+    }
+    switch transform.border {
+        case BORDER_CONSTANT: {
+            store(dst, transform.border_value / 255.0);
+            return false;
+        }
+        case BORDER_TRANSPARENT: {
+            return false;
+        }
+        default: {
+            return true;
+        }
+    }
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_none(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    // The last row and column of tiles overhang the image
+    if global_id.x >= transform.dst_dimensions.x || global_id.y >= transform.dst_dimensions.y {
+        return;
+    }
+
+    let p = floor(map_pos(global_id));
+    let dst = vec2<i32>(global_id.xy);
+    if !inside_or_border(p, dst) {
+        return;
+    }
+    // Centre of the texel, so the nearest filter picks exactly it
+    let uv = (p + 0.5) / vec2<f32>(transform.src_dimensions);
+    store(dst, textureSampleLevel(input, input_sampler, uv, 0.0));
+}
+
+// Texel `q`, or the constant border pixel outside the source
+fn tap(q: vec2<i32>) -> vec4<f32> {
+    let size = vec2<i32>(transform.src_dimensions);
+    if q.x < 0 || q.y < 0 || q.x >= size.x || q.y >= size.y {
+        return transform.border_value / 255.0;
+    }
+    return textureLoad(input, q, 0);
+}
+
+@compute
+@workgroup_size(TILE_X, TILE_Y)
+fn interpolation_bilinear(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if global_id.x >= transform.dst_dimensions.x || global_id.y >= transform.dst_dimensions.y {
+        return;
+    }
+
+    let p = map_pos(global_id);
+    let dst = vec2<i32>(global_id.xy);
+    // The sampler clamps taps that straddle the edge, so constant and
+    // transparent borders have to look at all four of them like the buffer
+    // path does
+    let q = vec2<i32>(floor(p));
+    let size = vec2<i32>(transform.src_dimensions);
+    let straddles = q.x < 0 || q.y < 0 || q.x + 1 >= size.x || q.y + 1 >= size.y;
+    if straddles {
+        switch transform.border {
+            case BORDER_CONSTANT: {
+                let f = p - floor(p);
+                let top = mix(tap(q), tap(q + vec2<i32>(1, 0)), f.x);
+                let bottom = mix(tap(q + vec2<i32>(0, 1)), tap(q + vec2<i32>(1, 1)), f.x);
+                store(dst, mix(top, bottom, f.y));
+                return;
+            }
+            case BORDER_TRANSPARENT: {
+                return;
+            }
+            default: {}
+        }
+    }
+    // Texel centres sit at integer source positions, as in the buffer path
+    let uv = (p + 0.5) / vec2<f32>(transform.src_dimensions);
+    store(dst, textureSampleLevel(input, input_sampler, uv, 0.0));
+}
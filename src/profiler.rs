@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    error::WError,
+    setup::{WState, WTsQueryState},
+};
+
+/// Named GPU timestamp scopes on top of [`WTsQueryState`].
+///
+/// Wrap work recorded on an encoder in [`WProfiler::scope`], call
+/// [`WProfiler::resolve`] on the last encoder of the frame before submitting
+/// it and collect the timings with [`WProfiler::finish`]. The resolve copies
+/// the timestamps into a mappable buffer, so `finish` can be awaited together
+/// with the frame's other readbacks. A frame that was resolved but never
+/// finished, e.g. because another readback failed, is discarded by the next
+/// `scope`. Without `TIMESTAMP_QUERY` every call is a no-op and `finish`
+/// returns an empty map.
+///
+/// ```ignore
+/// let mut profiler = WProfiler::new(&state, 8);
+/// {
+///     let mut encoder = profiler.scope("warp", &mut encoder);
+///     let mut cpass = encoder.begin_compute_pass(&Default::default());
+///     // ...
+/// }
+/// profiler.resolve(&mut encoder);
+/// state.queue.submit(Some(encoder.finish()));
+/// let (data, ms) = futures::join!(state.read_buffer(&dst), profiler.finish());
+/// ```
+pub struct WProfiler<'a> {
+    state: &'a WState,
+    queries: Option<WTsQueryState<'a>>,
+    /// Name and begin/end query index of every finished scope
+    scopes: Vec<(String, u32, u32)>,
+    /// Set by `resolve` and cleared by `finish`
+    resolved: bool,
+}
+
+impl<'a> WProfiler<'a> {
+    /// Profiler for up to `max_scopes` scopes per frame. Further scopes are
+    /// not timed.
+    pub fn new(state: &'a WState, max_scopes: u32) -> Self {
+        let queries = state
+            .device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
+            .then(|| WTsQueryState::new(state, max_scopes * 2));
+        Self {
+            state,
+            queries,
+            scopes: Vec::new(),
+            resolved: false,
+        }
+    }
+
+    /// Whether the device supports timestamp queries.
+    pub fn is_enabled(&self) -> bool {
+        self.queries.is_some()
+    }
+
+    /// Times everything recorded through the returned guard, which derefs to
+    /// `encoder`, until it is dropped.
+    pub fn scope<'p, 'e>(
+        &'p mut self,
+        name: &str,
+        encoder: &'e mut wgpu::CommandEncoder,
+    ) -> WProfilerScope<'a, 'p, 'e> {
+        if self.resolved {
+            self.reset();
+        }
+        let begin = self.queries.as_mut().and_then(|q| q.write(encoder));
+        WProfilerScope {
+            profiler: self,
+            encoder,
+            name: name.to_string(),
+            begin,
+        }
+    }
+
+    /// Resolves all queries of this frame. Record it after the last scope.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if let Some(queries) = &self.queries {
+            queries.resolve(encoder);
+        }
+        self.resolved = true;
+    }
+
+    /// Reads the resolved timestamps back and returns the milliseconds spent
+    /// per scope name; repeated names are summed. Resets the profiler for the
+    /// next frame, also when the readback fails.
+    pub async fn finish(&mut self) -> Result<HashMap<String, f64>, WError> {
+        let ts = match &self.queries {
+            Some(queries) => Some(queries.read().await),
+            None => None,
+        };
+        let scopes = std::mem::take(&mut self.scopes);
+        self.reset();
+
+        let mut ms = HashMap::new();
+        let Some(ts) = ts else {
+            return Ok(ms);
+        };
+        let ts = ts?;
+        let period = self.state.queue.get_timestamp_period() as f64;
+        for (name, begin, end) in scopes {
+            let ticks = ts[end as usize].saturating_sub(ts[begin as usize]);
+            *ms.entry(name).or_default() += ticks as f64 * period * 1e-6;
+        }
+        Ok(ms)
+    }
+
+    fn reset(&mut self) {
+        if let Some(queries) = &mut self.queries {
+            queries.reset();
+        }
+        self.scopes.clear();
+        self.resolved = false;
+    }
+}
+
+/// Guard returned by [`WProfiler::scope`]. Writes the closing timestamp when
+/// dropped.
+pub struct WProfilerScope<'a, 'p, 'e> {
+    profiler: &'p mut WProfiler<'a>,
+    encoder: &'e mut wgpu::CommandEncoder,
+    name: String,
+    begin: Option<u32>,
+}
+
+impl Deref for WProfilerScope<'_, '_, '_> {
+    type Target = wgpu::CommandEncoder;
+
+    fn deref(&self) -> &Self::Target {
+        self.encoder
+    }
+}
+
+impl DerefMut for WProfilerScope<'_, '_, '_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.encoder
+    }
+}
+
+impl Drop for WProfilerScope<'_, '_, '_> {
+    fn drop(&mut self) {
+        let Some(begin) = self.begin else {
+            return;
+        };
+        let queries = self.profiler.queries.as_mut().unwrap();
+        if let Some(end) = queries.write(self.encoder) {
+            let name = std::mem::take(&mut self.name);
+            self.profiler.scopes.push((name, begin, end));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pollster::FutureExt;
+
+    #[test]
+    fn unfinished_frame_is_discarded() {
+        let state = WState::new().block_on().unwrap();
+        let mut profiler = WProfiler::new(&state, 1);
+
+        // Resolved, but its readback never happened
+        let mut encoder = state.device.create_command_encoder(&Default::default());
+        drop(profiler.scope("stale", &mut encoder));
+        profiler.resolve(&mut encoder);
+        drop(encoder);
+
+        let mut encoder = state.device.create_command_encoder(&Default::default());
+        drop(profiler.scope("warp", &mut encoder));
+        profiler.resolve(&mut encoder);
+        state.queue.submit(Some(encoder.finish()));
+        let ms = profiler.finish().block_on().unwrap();
+        assert!(!ms.contains_key("stale"));
+        assert_eq!(ms.contains_key("warp"), profiler.is_enabled());
+    }
+}
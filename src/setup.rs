@@ -1,39 +1,351 @@
+use std::{
+    future::Future,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    thread,
+};
+
+use bytemuck::Pod;
+use wgpu::util::DeviceExt;
+
+use crate::error::WError;
+use crate::image::Size;
+use crate::types::{WDevToHost, WHostToDev};
+
 pub struct WState {
-    pub device: wgpu::Device,
+    pub device: Arc<wgpu::Device>,
     pub queue: wgpu::Queue,
+    pub adapter_info: wgpu::AdapterInfo,
+    /// Workgroup tile the warp shaders are compiled with
+    pub tile_size: WTileSize,
+    poller: WPoller,
+}
+
+/// Unmaps a buffer `map_read` has started to map once the read is over,
+/// including when its future is dropped before the mapping finished. wgpu
+/// aborts a pending mapping on unmap, so the buffer can be mapped again
+/// either way. Failed mappings leave nothing to unmap.
+struct WUnmapGuard<'b> {
+    buf: &'b wgpu::Buffer,
+    failed: Arc<AtomicBool>,
+}
+
+impl Drop for WUnmapGuard<'_> {
+    fn drop(&mut self) {
+        if !self.failed.load(Ordering::Acquire) {
+            self.buf.unmap();
+        }
+    }
+}
+
+/// Drives pending buffer mappings on a dedicated thread, so awaiting a
+/// readback never blocks the executor. Every request makes the thread run one
+/// `Maintain::Wait` poll; requests that pile up meanwhile share that poll. The
+/// thread exits once the owning [`WState`] is dropped.
+struct WPoller {
+    requests: mpsc::Sender<()>,
+}
+
+impl WPoller {
+    fn spawn(device: Arc<wgpu::Device>) -> Self {
+        let (requests, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("rustwarp-poll".into())
+            .spawn(move || {
+                while receiver.recv().is_ok() {
+                    while receiver.try_recv().is_ok() {}
+                    device.poll(wgpu::Maintain::Wait);
+                }
+            })
+            .expect("failed to spawn the device poll thread");
+        Self { requests }
+    }
+
+    fn request(&self) {
+        // The thread only stops after the sender is gone
+        let _ = self.requests.send(());
+    }
 }
 
 impl WState {
-    pub async fn new() -> Self {
-        // let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::PRIMARY,
+    /// Creates a state with the default [`WStateBuilder`], i.e. honoring
+    /// `WGPU_BACKEND`, `WGPU_POWER_PREF` and `WGPU_ADAPTER_NAME`.
+    pub async fn new() -> Result<Self, WError> {
+        WStateBuilder::default().build().await
+    }
+
+    pub fn builder() -> WStateBuilder {
+        WStateBuilder::default()
+    }
+
+    /// Queues an upload of `data` to `buf` at `offset`. The offset and length
+    /// must be multiples of `wgpu::COPY_BUFFER_ALIGNMENT` and fit in `buf`.
+    pub fn write<T: WHostToDev + ?Sized>(
+        &self,
+        buf: &wgpu::Buffer,
+        offset: u64,
+        data: &T,
+    ) -> Result<(), WError> {
+        let bytes = data.bytes();
+        let len = bytes.len() as u64;
+        let align = wgpu::COPY_BUFFER_ALIGNMENT;
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(WError::ByteLength {
+                expected: len.next_multiple_of(align) as usize,
+                got: bytes.len(),
+            });
+        }
+        if offset + len > buf.size() {
+            return Err(WError::ByteLength {
+                expected: buf.size().saturating_sub(offset) as usize,
+                got: bytes.len(),
+            });
+        }
+        self.queue.write_buffer(buf, offset, bytes);
+        Ok(())
+    }
+
+    /// Maps the whole `MAP_READ` buffer `buf`, hands its contents to `f` and
+    /// unmaps it again. Work submitted before the call is waited for on the
+    /// poll thread; the returned future just sleeps until the mapping is done.
+    async fn map_read<R>(
+        &self,
+        buf: &wgpu::Buffer,
+        f: impl FnOnce(&[u8]) -> Result<R, WError>,
+    ) -> Result<R, WError> {
+        let slice = buf.slice(..);
+        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+        let failed = Arc::new(AtomicBool::new(false));
+        let flag = failed.clone();
+        // The receiver is gone if the read future was dropped meanwhile
+        slice.map_async(wgpu::MapMode::Read, move |v| {
+            if v.is_err() {
+                flag.store(true, Ordering::Release);
+            }
+            let _ = sender.send(v);
+        });
+        let _unmap = WUnmapGuard { buf, failed };
+        self.poller.request();
+        match receiver.receive().await {
+            Some(Ok(())) => {
+                // Dropped before the guard unmaps
+                let view = slice.get_mapped_range();
+                f(&view)
+            }
+            Some(Err(e)) => Err(e.into()),
+            None => Err(WError::BufferMap(wgpu::BufferAsyncError)),
+        }
+    }
+
+    /// Reads the `MAP_READ` buffer `buf` into `out`, whose size must match.
+    pub async fn read_into<T: WDevToHost + ?Sized>(
+        &self,
+        buf: &wgpu::Buffer,
+        out: &mut T,
+    ) -> Result<(), WError> {
+        self.map_read(buf, |bytes| out.from_bytes(bytes)).await
+    }
+
+    /// Reads the `MAP_READ` buffer `buf` into a new value.
+    pub async fn read<T: WDevToHost>(&self, buf: &wgpu::Buffer) -> Result<T, WError> {
+        self.map_read(buf, T::from_bytes_new).await
+    }
+
+    /// Reads `buf` back once all previously submitted work is done. Buffers
+    /// without `MAP_READ` are copied through their staging buffer first.
+    ///
+    /// The future does not block: many readbacks, e.g. of several warp
+    /// engines, can be awaited concurrently on one runtime. Concurrent reads
+    /// of the same buffer take turns, since a buffer can only be mapped once
+    /// at a time.
+    pub fn read_buffer<'b, T: Pod>(
+        &'b self,
+        buf: &'b WBuffer<'_, T>,
+    ) -> impl Future<Output = Result<Vec<T>, WError>> + 'b {
+        async move {
+            let _reading = buf.reading.lock().await;
+            let mut out = vec![T::zeroed(); buf.len];
+            if buf.usage.contains(wgpu::BufferUsages::MAP_READ) {
+                self.read_into(&buf.buffer, out.as_mut_slice()).await?;
+                return Ok(out);
+            }
+            let staging = buf.staging();
+            let mut encoder = self.device.create_command_encoder(&Default::default());
+            encoder.copy_buffer_to_buffer(&buf.buffer, 0, staging, 0, buf.size_bytes());
+            self.queue.submit(Some(encoder.finish()));
+            self.read_into(staging, out.as_mut_slice()).await?;
+            Ok(out)
+        }
+    }
+
+    /// Runs `f` inside a validation error scope. Shader compilation and
+    /// pipeline creation errors are reported as [`WError::ShaderCompile`]
+    /// instead of hitting wgpu's default panicking error handler.
+    pub async fn validate<R>(&self, f: impl FnOnce(&wgpu::Device) -> R) -> Result<R, WError> {
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let r = f(&self.device);
+        match self.device.pop_error_scope().await {
+            Some(e) => Err(WError::ShaderCompile(e.to_string())),
+            None => Ok(r),
+        }
+    }
+}
+
+/// Adapter and device selection for [`WState`].
+///
+/// Defaults to the primary backends on a high performance adapter. The
+/// `WGPU_BACKEND` (e.g. `vulkan,gl`), `WGPU_POWER_PREF` (`low` / `high`) and
+/// `WGPU_ADAPTER_NAME` environment variables override those defaults, and the
+/// builder methods override the environment.
+#[derive(Clone, Debug)]
+pub struct WStateBuilder {
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+    pub force_fallback_adapter: bool,
+    pub required_features: wgpu::Features,
+    pub required_limits: wgpu::Limits,
+    pub adapter_name: Option<String>,
+    /// `None` picks [`WTileSize::for_limits`] of the device
+    pub tile_size: Option<WTileSize>,
+}
+
+impl Default for WStateBuilder {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::PRIMARY),
+            power_preference: wgpu::util::power_preference_from_env()
+                .unwrap_or(wgpu::PowerPreference::HighPerformance),
+            force_fallback_adapter: false,
+            required_features: wgpu::Features::empty(),
+            required_limits: Default::default(),
+            adapter_name: std::env::var("WGPU_ADAPTER_NAME").ok(),
+            tile_size: None,
+        }
+    }
+}
+
+impl WStateBuilder {
+    pub fn backends(mut self, backends: wgpu::Backends) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    pub fn power_preference(mut self, power_preference: wgpu::PowerPreference) -> Self {
+        self.power_preference = power_preference;
+        self
+    }
+
+    /// Only accept a software adapter such as lavapipe or WARP.
+    pub fn force_fallback_adapter(mut self, force: bool) -> Self {
+        self.force_fallback_adapter = force;
+        self
+    }
+
+    /// Features the device must support. `TIMESTAMP_QUERY` is always
+    /// requested on top of these when the adapter has it.
+    pub fn required_features(mut self, features: wgpu::Features) -> Self {
+        self.required_features = features;
+        self
+    }
+
+    pub fn required_limits(mut self, limits: wgpu::Limits) -> Self {
+        self.required_limits = limits;
+        self
+    }
+
+    /// Pick the first adapter whose name contains `name` (case insensitive).
+    pub fn adapter_name(mut self, name: impl Into<String>) -> Self {
+        self.adapter_name = Some(name.into());
+        self
+    }
+
+    /// Workgroup tile of the warp shaders; checked against the device limits
+    /// in [`WStateBuilder::build`].
+    pub fn tile_size(mut self, tile_size: WTileSize) -> Self {
+        self.tile_size = Some(tile_size);
+        self
+    }
+
+    fn instance(&self) -> wgpu::Instance {
+        wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: self.backends,
             flags: wgpu::InstanceFlags::default(),
             ..Default::default()
-        });
+        })
+    }
 
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::HighPerformance,
-                compatible_surface: None,
-                force_fallback_adapter: false,
-            })
+    /// Lists every adapter visible on the selected backends.
+    pub fn enumerate_adapters(&self) -> Vec<wgpu::AdapterInfo> {
+        self.instance()
+            .enumerate_adapters(self.backends)
+            .iter()
+            .map(|adapter| adapter.get_info())
+            .collect()
+    }
+
+    async fn request_adapter(&self, instance: &wgpu::Instance) -> Option<wgpu::Adapter> {
+        match &self.adapter_name {
+            Some(name) => {
+                let name = name.to_lowercase();
+                instance
+                    .enumerate_adapters(self.backends)
+                    .into_iter()
+                    .filter(|adapter| {
+                        !self.force_fallback_adapter
+                            || adapter.get_info().device_type == wgpu::DeviceType::Cpu
+                    })
+                    .find(|adapter| adapter.get_info().name.to_lowercase().contains(&name))
+            }
+            None => {
+                instance
+                    .request_adapter(&wgpu::RequestAdapterOptions {
+                        power_preference: self.power_preference,
+                        compatible_surface: None,
+                        force_fallback_adapter: self.force_fallback_adapter,
+                    })
+                    .await
+            }
+        }
+    }
+
+    pub async fn build(self) -> Result<WState, WError> {
+        const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::TIMESTAMP_QUERY;
+
+        let instance = self.instance();
+        let adapter = self
+            .request_adapter(&instance)
             .await
-            .unwrap();
+            .ok_or(WError::NoAdapter)?;
 
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
                     label: None,
-                    required_features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
-                    required_limits: Default::default(),
+                    required_features: self.required_features
+                        | (adapter.features() & OPTIONAL_FEATURES),
+                    required_limits: self.required_limits,
                 },
                 None,
             )
-            .await
-            .unwrap();
-
-        Self { device, queue }
+            .await?;
+
+        let limits = device.limits();
+        let tile_size = self
+            .tile_size
+            .unwrap_or_else(|| WTileSize::for_limits(&limits));
+        tile_size.check(&limits)?;
+
+        let device = Arc::new(device);
+        Ok(WState {
+            tile_size,
+            poller: WPoller::spawn(device.clone()),
+            device,
+            queue,
+            adapter_info: adapter.get_info(),
+        })
     }
 }
 
@@ -54,7 +366,7 @@ macro_rules! wbyte_of {
 #[macro_export]
 macro_rules! wstring_replace {
     ($str:expr, $from:expr, $to:expr) => {
-        $str.replace($v, $to)
+        $str.replace($from, $to)
     };
     ($str:expr, [$(($from:expr, $to:expr)),*]) => {
         $str
@@ -270,69 +582,362 @@ macro_rules! wgpu_bind_group {
     };
 }
 
-pub struct WTsQueryState {
+/// GPU buffer of `len` elements of `T`.
+///
+/// Buffers without `MAP_READ` always get `COPY_SRC | COPY_DST` on top of the
+/// requested usage, so they can be uploaded to, copied and read back. The
+/// `MAP_READ` staging buffer [`WState::read_buffer`] needs for them is
+/// created on first use and kept; reads of one buffer are serialized so they
+/// never map it twice.
+pub struct WBuffer<'a, T: Pod> {
+    state: &'a WState,
+    buffer: wgpu::Buffer,
+    len: usize,
+    usage: wgpu::BufferUsages,
+    staging: OnceLock<wgpu::Buffer>,
+    /// Held by [`WState::read_buffer`] while the buffer or its staging buffer
+    /// is mapped
+    reading: futures_intrusive::sync::Mutex<()>,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: Pod> WBuffer<'a, T> {
+    fn full_usage(usage: wgpu::BufferUsages) -> wgpu::BufferUsages {
+        match usage.contains(wgpu::BufferUsages::MAP_READ) {
+            true => usage,
+            false => usage | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+        }
+    }
+
+    /// Uninitialised (zeroed) buffer for `len` elements.
+    pub fn new(state: &'a WState, label: &str, len: usize, usage: wgpu::BufferUsages) -> Self {
+        let usage = Self::full_usage(usage);
+        let buffer = wgpu_buf!(
+            state.device,
+            (len * std::mem::size_of::<T>()) as u64,
+            usage,
+            false,
+            label = Some(label)
+        );
+        Self::wrap(state, buffer, len, usage)
+    }
+
+    /// Buffer holding a copy of `data`.
+    pub fn from_slice(
+        state: &'a WState,
+        label: &str,
+        data: &[T],
+        usage: wgpu::BufferUsages,
+    ) -> Self {
+        let usage = Self::full_usage(usage);
+        let buffer = wgpu_buf_init!(state.device, data.bytes(), usage, label = Some(label));
+        Self::wrap(state, buffer, data.len(), usage)
+    }
+
+    fn wrap(
+        state: &'a WState,
+        buffer: wgpu::Buffer,
+        len: usize,
+        usage: wgpu::BufferUsages,
+    ) -> Self {
+        Self {
+            state,
+            buffer,
+            len,
+            usage,
+            staging: OnceLock::new(),
+            reading: futures_intrusive::sync::Mutex::new((), false),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn usage(&self) -> wgpu::BufferUsages {
+        self.usage
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        (self.len * std::mem::size_of::<T>()) as u64
+    }
+
+    /// The underlying buffer, e.g. for `resolve_query_set`.
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn as_binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+
+    fn staging(&self) -> &wgpu::Buffer {
+        self.staging.get_or_init(|| {
+            wgpu_buf!(
+                "Staging buffer",
+                self.state.device,
+                self.size_bytes(),
+                [MAP_READ | COPY_DST],
+                false
+            )
+        })
+    }
+
+    /// Reads the buffer back once all previously submitted work is done; see
+    /// [`WState::read_buffer`].
+    pub async fn read_async(&self) -> Result<Vec<T>, WError> {
+        self.state.read_buffer(self).await
+    }
+
+    /// Writes `data` to the start of the buffer.
+    pub fn upload(&self, data: &[T]) -> Result<(), WError> {
+        self.write_range(0, data)
+    }
+
+    /// Writes `data` starting at element `offset`.
+    pub fn write_range(&self, offset: usize, data: &[T]) -> Result<(), WError> {
+        if offset + data.len() > self.len {
+            return Err(WError::CountMismatch {
+                expected: self.len.saturating_sub(offset),
+                got: data.len(),
+            });
+        }
+        let offset = (offset * std::mem::size_of::<T>()) as u64;
+        self.state.write(&self.buffer, offset, data)
+    }
+
+    /// Copies the whole buffer to the start of `dst`.
+    pub fn copy_to(&self, dst: &WBuffer<'_, T>) -> Result<(), WError> {
+        if dst.len < self.len {
+            return Err(WError::CountMismatch {
+                expected: self.len,
+                got: dst.len,
+            });
+        }
+        let mut encoder = self
+            .state
+            .device
+            .create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &dst.buffer, 0, self.size_bytes());
+        self.state.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+}
+
+/// 2D workgroup size of the warp shaders. Every invocation writes one word of
+/// the output, i.e. [`WPixel::PIXELS_PER_WORD`](crate::image::WPixel::PIXELS_PER_WORD)
+/// pixels of a row, so a `w x h` image takes
+/// `ceil(ceil(w / PIXELS_PER_WORD) / x) x ceil(h / y)` workgroups (see
+/// [`WPixel::dispatch_size`](crate::image::WPixel::dispatch_size)).
+///
+/// `x` and `y` are bounded by the device's `max_compute_workgroup_size_x` /
+/// `_y` and `x * y` by `max_compute_invocations_per_workgroup`. That is 256 in
+/// both the default and the downlevel limits, so 16x16 fits everywhere. The
+/// workgroup count per dimension is capped by
+/// `max_compute_workgroups_per_dimension` (65535 by default), which a 16 word
+/// tile stretches to images of about a million words per side.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WTileSize {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Default for WTileSize {
+    fn default() -> Self {
+        Self::new(16, 16)
+    }
+}
+
+impl WTileSize {
+    pub fn new(x: u32, y: u32) -> Self {
+        Self { x, y }
+    }
+
+    /// The largest square tile up to 16x16 that `limits` allow.
+    pub fn for_limits(limits: &wgpu::Limits) -> Self {
+        let mut side = 16;
+        while side > 1 && Self::new(side, side).check(limits).is_err() {
+            side /= 2;
+        }
+        Self::new(side, side)
+    }
+
+    pub fn check(&self, limits: &wgpu::Limits) -> Result<(), WError> {
+        let invocations = self.x as u64 * self.y as u64;
+        if self.x == 0
+            || self.y == 0
+            || self.x > limits.max_compute_workgroup_size_x
+            || self.y > limits.max_compute_workgroup_size_y
+            || invocations > limits.max_compute_invocations_per_workgroup as u64
+        {
+            return Err(WError::TileSize {
+                x: self.x,
+                y: self.y,
+            });
+        }
+        Ok(())
+    }
+
+    /// Workgroups to dispatch to cover `size`.
+    pub fn workgroups(&self, size: Size) -> (u32, u32) {
+        (
+            (size.x as u32).div_ceil(self.x),
+            (size.y as u32).div_ceil(self.y),
+        )
+    }
+
+    /// Sets the `TILE_X` and `TILE_Y` constants of a warp shader.
+    pub fn apply(&self, shader: &str) -> String {
+        wstring_replace!(
+            shader,
+            [
+                (
+                    "const TILE_X: u32 = 16u;",
+                    &format!("const TILE_X: u32 = {}u;", self.x)
+                ),
+                (
+                    "const TILE_Y: u32 = 16u;",
+                    &format!("const TILE_Y: u32 = {}u;", self.y)
+                )
+            ]
+        )
+    }
+}
+
+/// A timestamp query set with a buffer to resolve it into. Queries are handed
+/// out in order with [`WTsQueryState::write`] until `max_count` is reached.
+pub struct WTsQueryState<'a> {
+    state: &'a WState,
     pub max_count: u32,
     pub current: u32,
     pub set: wgpu::QuerySet,
-    pub buf: wgpu::Buffer,
-    pub out: wgpu::Buffer,
+    pub buf: WBuffer<'a, u64>,
+    /// `MAP_READ` copy of `buf`, filled in the same encoder as the resolve so
+    /// reading it back needs no submit of its own
+    pub readback: WBuffer<'a, u64>,
 }
 
-impl WTsQueryState {
-    pub fn new(dev: &wgpu::Device, c: u32) -> Self {
+impl<'a> WTsQueryState<'a> {
+    pub fn new(state: &'a WState, c: u32) -> Self {
         WTsQueryState {
+            state,
             max_count: c,
             current: 0,
-            set: dev.create_query_set(&wgpu::QuerySetDescriptor {
+            set: state.device.create_query_set(&wgpu::QuerySetDescriptor {
                 label: None,
                 count: c,
                 ty: wgpu::QueryType::Timestamp,
             }),
-            buf: wgpu_buf!(
-                dev,
-                c as u64 * 8,
-                [QUERY_RESOLVE | STORAGE | COPY_SRC | COPY_DST],
-                false
+            buf: WBuffer::new(
+                state,
+                "Timestamp query buffer",
+                c as usize,
+                wgpu::BufferUsages::QUERY_RESOLVE,
+            ),
+            readback: WBuffer::new(
+                state,
+                "Timestamp readback buffer",
+                c as usize,
+                wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
             ),
-            out: wgpu_buf!(dev, 16, [MAP_READ | COPY_DST], false),
         }
     }
 
-    pub fn write(&mut self, encoder: &mut wgpu::CommandEncoder) {
-        assert!(
-            self.current < self.max_count,
-            "Timestamp query count exceeded"
-        );
+    /// Writes the next timestamp and returns its index, or `None` once all
+    /// `max_count` queries are used up.
+    pub fn write(&mut self, encoder: &mut wgpu::CommandEncoder) -> Option<u32> {
+        if self.current >= self.max_count {
+            return None;
+        }
         encoder.write_timestamp(&self.set, self.current);
         self.current += 1;
+        Some(self.current - 1)
     }
 
+    /// Resolves the queries written so far into `buf` and copies them to
+    /// `readback`.
     pub fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
-        encoder.resolve_query_set(&self.set, 0..self.max_count, &self.buf, 0);
-        encoder.copy_buffer_to_buffer(&self.buf, 0, &self.out, 0, self.max_count as u64 * 8);
-    }
-
-    pub fn map_async(&mut self) -> (usize, wgpu::BufferSlice) {
-        let query_slice = self.out.slice(..);
-        query_slice.map_async(wgpu::MapMode::Read, move |_| ());
-        (self.max_count as usize, query_slice)
+        if self.current > 0 {
+            encoder.resolve_query_set(&self.set, 0..self.current, self.buf.buffer(), 0);
+            let size = self.current as u64 * std::mem::size_of::<u64>() as u64;
+            encoder.copy_buffer_to_buffer(self.buf.buffer(), 0, self.readback.buffer(), 0, size);
+        }
     }
 
-    pub fn read(count: usize, slice: wgpu::BufferSlice) -> Vec<u64> {
-        let data = slice.get_mapped_range();
-        let mut result = Vec::with_capacity(count);
-        for i in 0..count {
-            let start = i as usize * 8;
-            let end = start + 8;
-            let bytes = &data[start..end];
-            let value = u64::from_ne_bytes(bytes.try_into().unwrap());
-            result.push(value);
-        }
-        result
+    /// Reads the resolved timestamps, one per written query, once the
+    /// encoder that resolved them has been submitted.
+    pub async fn read(&self) -> Result<Vec<u64>, WError> {
+        let mut ts = self.state.read_buffer(&self.readback).await?;
+        ts.truncate(self.current as usize);
+        Ok(ts)
     }
 
     pub fn reset(&mut self) {
         self.current = 0;
-        self.out.unmap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tile_size_follows_limits() {
+        let limits = wgpu::Limits::downlevel_defaults();
+        assert_eq!(WTileSize::for_limits(&limits), WTileSize::new(16, 16));
+
+        let small = wgpu::Limits {
+            max_compute_invocations_per_workgroup: 64,
+            ..limits.clone()
+        };
+        assert_eq!(WTileSize::for_limits(&small), WTileSize::new(8, 8));
+        assert!(WTileSize::new(32, 16).check(&limits).is_err());
+        assert!(WTileSize::new(32, 8).check(&limits).is_ok());
+
+        let tile = WTileSize::new(16, 8);
+        assert_eq!(tile.workgroups(Size::new(33, 16)), (3, 2));
+        assert_eq!(tile.workgroups(Size::new(16, 1)), (1, 1));
+        assert!(tile
+            .apply("const TILE_X: u32 = 16u;\nconst TILE_Y: u32 = 16u;")
+            .ends_with("TILE_Y: u32 = 8u;"));
+    }
+
+    #[test]
+    fn concurrent_reads_of_one_buffer() {
+        use futures::FutureExt as _;
+        use pollster::FutureExt;
+
+        let state = WState::new().block_on().unwrap();
+        let data: Vec<u32> = (0..64).collect();
+        let buf = WBuffer::from_slice(&state, "Test buffer", &data, wgpu::BufferUsages::STORAGE);
+
+        // Both map the same staging buffer
+        let (a, b) =
+            futures::future::join(state.read_buffer(&buf), state.read_buffer(&buf)).block_on();
+        assert_eq!(a.unwrap(), data);
+        assert_eq!(b.unwrap(), data);
+
+        // A read dropped while its mapping is pending must not keep the
+        // staging buffer mapped
+        assert!(matches!(
+            state.read_buffer(&buf).now_or_never(),
+            None | Some(Ok(_))
+        ));
+        assert_eq!(buf.read_async().block_on().unwrap(), data);
+
+        // Readbacks have to be movable to other threads, e.g. to be spawned
+        // on a multi-threaded runtime
+        let reads = [state.read_buffer(&buf), state.read_buffer(&buf)];
+        thread::scope(|s| {
+            for read in reads {
+                let data = &data;
+                s.spawn(move || assert_eq!(&read.block_on().unwrap(), data));
+            }
+        });
     }
 }
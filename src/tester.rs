@@ -1,7 +1,6 @@
 use bytemuck::{Pod, Zeroable};
 use pollster::FutureExt;
 use rand::{distributions::Standard, prelude::*};
-use wgpu::util::DeviceExt;
 
 use crate::setup::*;
 
@@ -27,11 +26,12 @@ pub trait WTestable {
             WType::Struct(inner) => inner,
         }
     }
-}
 
-impl WTestable for bool {
-    fn wgsl_type() -> WType {
-        WType::Primitive("bool")
+    /// Body of the test shader, with `index`, `input` and `output` in scope.
+    /// Copies by default, which checks the layout; override it to check a
+    /// WGSL builtin against its host equivalent.
+    fn wgsl_test_body() -> &'static str {
+        "output[index] = input[index];"
     }
 }
 
@@ -76,9 +76,8 @@ where
 {
     let mut rng = thread_rng();
     let input_values: Vec<T> = (0..n).map(|_| rng.gen()).collect();
-    let input_bytes = wbyte_cast!(&input_values);
 
-    const SHADER: &'static str = r#"
+    const SHADER: &str = r#"
         {struct_wgsl_type}
     
         @group(0)
@@ -93,14 +92,15 @@ where
         @workgroup_size(1)
         fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
             let index = global_id.x;
-            output[index] = input[index];
+            {body}
         }
     "#;
+    let shader = SHADER.replace("{body}", T::wgsl_test_body());
     let shader = match T::wgsl_type() {
-        WType::Primitive(t) => SHADER
+        WType::Primitive(t) => shader
             .replace("{struct_wgsl_type}", "")
             .replace("{wgsl_type}", t),
-        WType::Struct(t) => SHADER
+        WType::Struct(t) => shader
             .replace(
                 "{struct_wgsl_type}",
                 format!("struct StructType {{ {} }};", t).as_str(),
@@ -110,30 +110,13 @@ where
 
     // async anoynmous block
     let output_result = async {
-        let state = WState::new().await;
+        let state = WState::new().await?;
 
         let cs_module = wgpu_shader_load!("Compute shader", state.device, shader);
 
-        let input_buf = wgpu_buf_init!(
-            "Input buffer",
-            state.device,
-            input_bytes,
-            [STORAGE | COPY_SRC]
-        );
-        let copied_buf = wgpu_buf!(
-            "Copied buffer",
-            state.device,
-            input_bytes.len() as u64,
-            [STORAGE | COPY_DST | COPY_SRC],
-            false
-        );
-        let output_buf = wgpu_buf!(
-            "Output buffer",
-            state.device,
-            input_bytes.len() as u64,
-            [MAP_READ | COPY_DST],
-            false
-        );
+        let storage = wgpu::BufferUsages::STORAGE;
+        let input_buf = WBuffer::from_slice(&state, "Input buffer", &input_values, storage);
+        let copied_buf = WBuffer::<T>::new(&state, "Copied buffer", n, storage);
 
         let bind_group_layout =
             wgpu_bind_group_layout_compute!(state.device, [(0, true), (1, false)]);
@@ -147,10 +130,7 @@ where
         let bind_group = wgpu_bind_group!(
             state.device,
             &bind_group_layout,
-            [
-                (0, input_buf.as_entire_binding()),
-                (1, copied_buf.as_entire_binding())
-            ]
+            [(0, input_buf.as_binding()), (1, copied_buf.as_binding())]
         );
 
         let mut encoder = state.device.create_command_encoder(&Default::default());
@@ -161,29 +141,16 @@ where
             cpass.set_bind_group(0, &bind_group, &[]);
             cpass.dispatch_workgroups(n as u32, 1, 1);
         }
-
-        encoder.copy_buffer_to_buffer(&copied_buf, 0, &output_buf, 0, input_bytes.len() as u64);
         state.queue.submit(Some(encoder.finish()));
 
-        let output_slice = output_buf.slice(..);
-        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-        output_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-
-        state.device.poll(wgpu::Maintain::Wait);
-
-        if let Some(Ok(())) = receiver.receive().await {
-            let data_raw = &*output_slice.get_mapped_range();
-            let data: &[T] = bytemuck::cast_slice(data_raw);
-            return Ok(data.to_vec());
-        }
-        Err(())
+        state.read_buffer(&copied_buf).await
     }
     .block_on();
 
     let output_values = match output_result {
         Ok(v) => v,
-        Err(_) => {
-            return WTestResult::TestError("Failed execute test!".to_string());
+        Err(e) => {
+            return WTestResult::TestError(format!("Failed execute test! {}", e));
         }
     };
 
@@ -209,8 +176,8 @@ mod tests {
                 #[allow(non_snake_case)]
                 fn [<wtest_ $typ _ $n>]() {
                     assert_eq!(
-                        crate::tester::perform_test::<$typ>($n),
-                        crate::tester::WTestResult::Success
+                        $crate::tester::perform_test::<$typ>($n),
+                        $crate::tester::WTestResult::Success
                     );
                 }
             }
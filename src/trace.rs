@@ -0,0 +1,221 @@
+use std::{
+    fmt::Write as _,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::modules::warp_perspective::WarpStats;
+
+/// Which timeline of the trace an event belongs to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WTrack {
+    Cpu,
+    Gpu,
+}
+
+impl WTrack {
+    fn tid(&self) -> u32 {
+        match self {
+            WTrack::Cpu => 0,
+            WTrack::Gpu => 1,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            WTrack::Cpu => "CPU",
+            WTrack::Gpu => "GPU",
+        }
+    }
+
+    fn category(&self) -> &'static str {
+        match self {
+            WTrack::Cpu => "cpu",
+            WTrack::Gpu => "gpu",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WTraceEvent {
+    pub name: String,
+    pub track: WTrack,
+    /// Start relative to the creation of the [`WTrace`]
+    pub start: Duration,
+    pub dur: Duration,
+}
+
+/// Collects CPU and GPU spans and writes them as Chrome trace event JSON,
+/// which `about:tracing` and Perfetto open directly.
+///
+/// Clones share the same event list, so one trace can be handed to several
+/// warp engines through their `trace` field. GPU timestamps run on their own
+/// clock, so GPU spans are placed at the submit of their command buffer with
+/// the duration the timestamp queries measured.
+#[derive(Clone)]
+pub struct WTrace {
+    epoch: Instant,
+    events: Arc<Mutex<Vec<WTraceEvent>>>,
+}
+
+impl Default for WTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WTrace {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn span(&self, track: WTrack, name: &str, start: Instant, dur: Duration) {
+        self.events.lock().unwrap().push(WTraceEvent {
+            name: name.to_string(),
+            track,
+            start: start.saturating_duration_since(self.epoch),
+            dur,
+        });
+    }
+
+    pub fn cpu(&self, name: &str, start: Instant, dur: Duration) {
+        self.span(WTrack::Cpu, name, start, dur);
+    }
+
+    pub fn gpu(&self, name: &str, start: Instant, dur: Duration) {
+        self.span(WTrack::Gpu, name, start, dur);
+    }
+
+    /// Records one warp call that began at `started`: a `name` span around
+    /// its buffer creation, upload, submit and map wait spans on the CPU, and
+    /// the kernel on the GPU.
+    pub fn record_warp(&self, name: &str, started: Instant, stats: &WarpStats) {
+        self.cpu(name, started, stats.total());
+        let mut at = started;
+        for (phase, dur) in [
+            ("buffer creation", stats.buffers),
+            ("upload", stats.upload),
+            ("submit", stats.dispatch),
+            ("map wait", stats.download),
+        ] {
+            if !dur.is_zero() {
+                self.cpu(phase, at, dur);
+            }
+            at += dur;
+            if phase == "submit" {
+                if let Some(kernel) = stats.kernel {
+                    self.gpu(&format!("{} kernel", name), at, kernel);
+                }
+            }
+        }
+    }
+
+    pub fn events(&self) -> Vec<WTraceEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"traceEvents\":[\n");
+        for track in [WTrack::Cpu, WTrack::Gpu] {
+            let _ = writeln!(
+                json,
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\
+                 \"args\":{{\"name\":\"{}\"}}}},",
+                track.tid(),
+                track.name()
+            );
+        }
+        let events = self.events();
+        for (i, e) in events.iter().enumerate() {
+            let _ = write!(
+                json,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\
+                 \"pid\":0,\"tid\":{}}}",
+                escape(&e.name),
+                e.track.category(),
+                e.start.as_secs_f64() * 1e6,
+                e.dur.as_secs_f64() * 1e6,
+                e.track.tid()
+            );
+            json.push_str(if i + 1 < events.len() { ",\n" } else { "\n" });
+        }
+        if events.is_empty() {
+            // Drop the trailing comma of the metadata events
+            json.truncate(json.len() - 2);
+            json.push('\n');
+        }
+        json.push_str("],\"displayTimeUnit\":\"ms\"}\n");
+        json
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json())
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warp_spans_are_laid_out_in_order() {
+        let trace = WTrace::new();
+        let started = trace.epoch + Duration::from_millis(1);
+        let stats = WarpStats {
+            buffers: Duration::ZERO,
+            upload: Duration::from_micros(100),
+            dispatch: Duration::from_micros(20),
+            kernel: Some(Duration::from_micros(50)),
+            download: Duration::from_micros(300),
+        };
+        trace.record_warp("warp", started, &stats);
+
+        let spans: Vec<_> = trace
+            .events()
+            .into_iter()
+            .map(|e| (e.name, e.track, e.start.as_micros(), e.dur.as_micros()))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                ("warp".to_string(), WTrack::Cpu, 1000, 420),
+                ("upload".to_string(), WTrack::Cpu, 1000, 100),
+                ("submit".to_string(), WTrack::Cpu, 1100, 20),
+                ("warp kernel".to_string(), WTrack::Gpu, 1120, 50),
+                ("map wait".to_string(), WTrack::Cpu, 1120, 300),
+            ]
+        );
+
+        let json = trace.to_json();
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert!(json.contains(
+            "{\"name\":\"warp kernel\",\"cat\":\"gpu\",\"ph\":\"X\",\"ts\":1120.000,\
+             \"dur\":50.000,\"pid\":0,\"tid\":1}"
+        ));
+        assert!(json.trim_end().ends_with("],\"displayTimeUnit\":\"ms\"}"));
+        assert_eq!(escape("a\"b\\c\n"), "a\\\"b\\\\c\\u000a");
+    }
+}
@@ -1,25 +1,330 @@
 use core::fmt;
-use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::{
+    collections::HashMap,
+    ops::{Add, Div, Mul, Neg, Sub},
+    sync::{Mutex, OnceLock},
+};
 
 use bytemuck::{Pod, Zeroable};
 use paste::paste;
 
+use crate::error::WError;
+use crate::image::{Image, PointF, WPixel};
 use crate::tester::impl_prelude::*;
 
-// TODO: WScalars; AbstractInt, AbstractFloat, f16
+pub use half::f16;
+
+// AbstractInt and AbstractFloat only exist while a shader is compiled, so
+// there is nothing to share with the host. `f16` is host side only: the naga
+// that ships with wgpu 0.19 cannot parse `enable f16;`, so `wtest!` has no
+// `f16` round trip. Pairs of them reach shaders as a `u32` through
+// `pack2x16float`, which matches the WGSL builtin (see `wtest_packing!`).
 pub trait WScalars: Copy + Zeroable + Default {}
-impl WScalars for bool {}
+impl WScalars for WBool {}
 impl WScalars for u32 {}
 impl WScalars for i32 {}
 impl WScalars for f32 {}
+impl WScalars for f16 {}
+
+/// Host-shareable boolean. WGSL does not allow `bool` in buffers and not every
+/// byte is a valid Rust `bool`, so this is a `u32` on both sides: zero is
+/// false, anything else true. Test it with `x != 0u` in shaders.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, PartialEq, Eq, Hash)]
+pub struct WBool(pub u32);
+
+impl WBool {
+    pub const FALSE: Self = Self(0);
+    pub const TRUE: Self = Self(1);
+
+    pub fn get(self) -> bool {
+        self.0 != 0
+    }
+}
+
+impl From<bool> for WBool {
+    fn from(v: bool) -> Self {
+        Self(v as u32)
+    }
+}
+
+impl From<WBool> for bool {
+    fn from(v: WBool) -> Self {
+        v.get()
+    }
+}
+
+impl fmt::Display for WBool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+impl WTestable for WBool {
+    fn wgsl_type() -> WType {
+        WType::Primitive("u32")
+    }
+}
+
+impl WDistribution<WBool> for WStandard {
+    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> WBool {
+        WBool::from(rng.gen::<bool>())
+    }
+}
+
+/// Alignment and size of the WGSL type a Rust type stands for, from the
+/// alignment and size table of the WGSL spec. Used by `#[derive(WgslStruct)]`
+/// to reject misplaced fields at compile time.
+pub trait WLayout {
+    const ALIGN: usize;
+    const SIZE: usize;
+    /// Arrays and structs in the uniform address space round up to 16
+    const UNIFORM_ALIGN: usize = Self::ALIGN;
+    const UNIFORM_SIZE: usize = Self::SIZE;
+}
+
+pub const fn wround_up(align: usize, v: usize) -> usize {
+    v.div_ceil(align) * align
+}
+
+/// Alignment of a `vecN` of scalars of `scalar_size` bytes.
+pub const fn wvec_align(n: usize, scalar_size: usize) -> usize {
+    match n {
+        2 => 2 * scalar_size,
+        _ => 4 * scalar_size,
+    }
+}
+
+macro_rules! wlayout_scalar {
+    ($($t:ty),*) => {
+        $(
+            impl WLayout for $t {
+                const ALIGN: usize = 4;
+                const SIZE: usize = 4;
+            }
+        )*
+    };
+}
+
+wlayout_scalar!(WBool, u32, i32, f32);
+
+impl WLayout for f16 {
+    const ALIGN: usize = 2;
+    const SIZE: usize = 2;
+}
+
+// Host equivalents of the WGSL packing builtins, e.g. to prepare data for a
+// shader calling `unpack4x8unorm`. Component `i` ends up in bits `8i..8i+8`
+// (`16i..16i+16` for the 2x16 variants), like on the GPU.
+
+pub fn pack4x8unorm(v: [f32; 4]) -> u32 {
+    u32::from_le_bytes(v.map(|c| (0.5 + 255.0 * c.clamp(0.0, 1.0)).floor() as u8))
+}
+
+pub fn unpack4x8unorm(v: u32) -> [f32; 4] {
+    v.to_le_bytes().map(|c| c as f32 / 255.0)
+}
+
+pub fn pack4x8snorm(v: [f32; 4]) -> u32 {
+    u32::from_le_bytes(v.map(|c| (0.5 + 127.0 * c.clamp(-1.0, 1.0)).floor() as i8 as u8))
+}
+
+pub fn unpack4x8snorm(v: u32) -> [f32; 4] {
+    v.to_le_bytes().map(|c| (c as i8 as f32 / 127.0).max(-1.0))
+}
+
+pub fn pack2x16unorm(v: [f32; 2]) -> u32 {
+    let [a, b] = v.map(|c| (0.5 + 65535.0 * c.clamp(0.0, 1.0)).floor() as u32);
+    a | b << 16
+}
+
+pub fn unpack2x16unorm(v: u32) -> [f32; 2] {
+    [v & 0xffff, v >> 16].map(|c| c as f32 / 65535.0)
+}
+
+pub fn pack2x16snorm(v: [f32; 2]) -> u32 {
+    let [a, b] = v.map(|c| (0.5 + 32767.0 * c.clamp(-1.0, 1.0)).floor() as i16 as u16 as u32);
+    a | b << 16
+}
+
+pub fn unpack2x16snorm(v: u32) -> [f32; 2] {
+    [v & 0xffff, v >> 16].map(|c| (c as u16 as i16 as f32 / 32767.0).max(-1.0))
+}
+
+pub fn pack2x16float(v: [f32; 2]) -> u32 {
+    let [a, b] = v.map(|c| f16::from_f32(c).to_bits() as u32);
+    a | b << 16
+}
+
+pub fn unpack2x16float(v: u32) -> [f32; 2] {
+    [v & 0xffff, v >> 16].map(|c| f16::from_bits(c as u16).to_f32())
+}
+
+/// GPU check of a host packing helper and its inverse against the WGSL
+/// builtins: the shader packs `unpacked` and unpacks `packed`, which must give
+/// back the other field as the host computed it. Unpacking divides, so the
+/// floats may differ in the last bits.
+#[cfg(test)]
+macro_rules! wtest_packing {
+    ($name:ident, $pack:ident, $unpack:ident, $n:literal, $range:expr) => {
+        #[repr(C)]
+        #[derive(Copy, Clone, Debug, Zeroable, Pod)]
+        struct $name {
+            unpacked: [f32; $n],
+            packed: u32,
+            _pad: [u32; $n - 1],
+        }
+
+        // `vecN<f32>` is aligned to its size, so the WGSL struct is twice it
+        const _: () = assert!(std::mem::size_of::<$name>() == 8 * $n);
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.packed == other.packed
+                    && (self.unpacked.iter())
+                        .zip(other.unpacked.iter())
+                        .all(|(a, b)| (a - b).abs() <= 1e-6)
+            }
+        }
+
+        impl WTestable for $name {
+            fn wgsl_type() -> WType {
+                WType::Struct(concat!("unpacked: vec", $n, "<f32>, packed: u32"))
+            }
+
+            fn wgsl_test_body() -> &'static str {
+                concat!(
+                    "output[index].unpacked = ",
+                    stringify!($unpack),
+                    "(input[index].packed);\n",
+                    "output[index].packed = ",
+                    stringify!($pack),
+                    "(input[index].unpacked);"
+                )
+            }
+        }
+
+        impl WDistribution<$name> for WStandard {
+            fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> $name {
+                let packed = $pack([(); $n].map(|_| rng.gen_range($range)));
+                $name {
+                    unpacked: $unpack(packed),
+                    packed,
+                    _pad: [0; $n - 1],
+                }
+            }
+        }
+
+        wtest!($name, 256);
+    };
+}
+
+#[cfg(test)]
+wtest_packing!(WPack4x8Unorm, pack4x8unorm, unpack4x8unorm, 4, 0.0..=1.0);
+#[cfg(test)]
+wtest_packing!(WPack4x8Snorm, pack4x8snorm, unpack4x8snorm, 4, -1.0..=1.0);
+#[cfg(test)]
+wtest_packing!(WPack2x16Unorm, pack2x16unorm, unpack2x16unorm, 2, 0.0..=1.0);
+#[cfg(test)]
+wtest_packing!(
+    WPack2x16Snorm,
+    pack2x16snorm,
+    unpack2x16snorm,
+    2,
+    -1.0..=1.0
+);
+#[cfg(test)]
+wtest_packing!(
+    WPack2x16Float,
+    pack2x16float,
+    unpack2x16float,
+    2,
+    -1000.0..=1000.0
+);
 
+impl<T: WLayout, const N: usize> WLayout for [T; N] {
+    const ALIGN: usize = T::ALIGN;
+    const SIZE: usize = N * wround_up(T::ALIGN, T::SIZE);
+    const UNIFORM_ALIGN: usize = wround_up(16, T::UNIFORM_ALIGN);
+    const UNIFORM_SIZE: usize = N * wround_up(16, wround_up(T::UNIFORM_ALIGN, T::UNIFORM_SIZE));
+}
+
+/// Data that can be uploaded to a GPU buffer as is.
 pub trait WHostToDev {
     fn bytes(&self) -> &[u8];
 }
 
+/// Data that can be filled from the bytes of a GPU buffer. Both methods fail
+/// with [`WError::ByteLength`] if `bytes` does not have the expected length;
+/// `bytes` need not be aligned.
 pub trait WDevToHost {
-    fn from_bytes_new(bytes: &[u8]) -> Self;
-    fn from_bytes(&mut self, bytes: &[u8]);
+    fn from_bytes_new(bytes: &[u8]) -> Result<Self, WError>
+    where
+        Self: Sized;
+    #[allow(clippy::wrong_self_convention)]
+    fn from_bytes(&mut self, bytes: &[u8]) -> Result<(), WError>;
+}
+
+fn check_byte_length(expected: usize, got: usize) -> Result<(), WError> {
+    match expected == got {
+        true => Ok(()),
+        false => Err(WError::ByteLength { expected, got }),
+    }
+}
+
+impl<T: Pod> WHostToDev for T {
+    fn bytes(&self) -> &[u8] {
+        bytemuck::bytes_of(self)
+    }
+}
+
+impl<T: Pod> WHostToDev for [T] {
+    fn bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(self)
+    }
+}
+
+impl<T: Pod> WDevToHost for T {
+    fn from_bytes_new(bytes: &[u8]) -> Result<Self, WError> {
+        check_byte_length(std::mem::size_of::<T>(), bytes.len())?;
+        Ok(bytemuck::pod_read_unaligned(bytes))
+    }
+
+    fn from_bytes(&mut self, bytes: &[u8]) -> Result<(), WError> {
+        *self = T::from_bytes_new(bytes)?;
+        Ok(())
+    }
+}
+
+/// Slices are filled in place, `bytes` must cover exactly `self.len()`
+/// elements.
+impl<T: Pod> WDevToHost for [T] {
+    fn from_bytes(&mut self, bytes: &[u8]) -> Result<(), WError> {
+        let dst: &mut [u8] = bytemuck::cast_slice_mut(self);
+        check_byte_length(dst.len(), bytes.len())?;
+        dst.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl<P: WPixel> WHostToDev for Image<P> {
+    fn bytes(&self) -> &[u8] {
+        self.data.bytes()
+    }
+}
+
+/// An image read back without a known size comes out as a single row.
+impl<P: WPixel> WDevToHost for Image<P> {
+    fn from_bytes_new(bytes: &[u8]) -> Result<Self, WError> {
+        let n = bytes.len() / std::mem::size_of::<P>();
+        let mut im = Image::new(crate::image::Size::new(n, 1));
+        im.from_bytes(bytes)?;
+        Ok(im)
+    }
+
+    fn from_bytes(&mut self, bytes: &[u8]) -> Result<(), WError> {
+        self.data.from_bytes(bytes)
+    }
 }
 
 #[macro_export]
@@ -50,6 +355,233 @@ macro_rules! wvec_print_fields {
     };
 }
 
+macro_rules! wvec_len {
+    () => { 0 };
+    ($first:ident $(, $rest:ident)*) => { 1 + wvec_len!($($rest),*) };
+}
+
+macro_rules! wvec_binop {
+    ($vec:ident { $($fields:ident),* }, $trait:ident, $fn:ident, $op:tt) => {
+        impl<T: WScalars + $trait<Output = T>> $trait for $vec<T> {
+            type Output = Self;
+
+            fn $fn(self, rhs: Self) -> Self {
+                Self::new($(self.$fields $op rhs.$fields),*)
+            }
+        }
+
+        impl<T: WScalars + $trait<Output = T>> $trait<T> for $vec<T> {
+            type Output = Self;
+
+            fn $fn(self, rhs: T) -> Self {
+                Self::new($(self.$fields $op rhs),*)
+            }
+        }
+    };
+}
+
+/// Every WGSL swizzle of the components `$c` with 2 to 4 letters, repeats
+/// included (`xx`, `zyx`, `wwww`, ...), like WGSL allows on any vector. Each
+/// is a default trait method over the component getters and returns the
+/// unpadded vector of its length. Longer swizzles fix the leading letters one
+/// recursion level at a time and emit the last one as a repetition.
+macro_rules! wvec_swizzles {
+    ($($c:ident),*) => {
+        wvec_swizzles!(@2 [$($c),*] $($c)*);
+        wvec_swizzles!(@3 [$($c),*] $($c)*);
+        wvec_swizzles!(@4 [$($c),*] $($c)*);
+    };
+
+    (@2 [$($all:ident),*]) => {};
+    (@2 [$($all:ident),*] $a:ident $($rest:ident)*) => {
+        paste! {
+            $(
+                fn [< $a $all >](&self) -> WVec2P0<T> {
+                    WVec2P0::new(self.$a(), self.$all())
+                }
+            )*
+        }
+        wvec_swizzles!(@2 [$($all),*] $($rest)*);
+    };
+
+    (@3 [$($all:ident),*]) => {};
+    (@3 [$($all:ident),*] $a:ident $($rest:ident)*) => {
+        wvec_swizzles!(@3 [$($all),*] $a; $($all)*);
+        wvec_swizzles!(@3 [$($all),*] $($rest)*);
+    };
+    (@3 [$($all:ident),*] $a:ident;) => {};
+    (@3 [$($all:ident),*] $a:ident; $b:ident $($rest:ident)*) => {
+        paste! {
+            $(
+                fn [< $a $b $all >](&self) -> WVec3P0<T> {
+                    WVec3P0::new(self.$a(), self.$b(), self.$all())
+                }
+            )*
+        }
+        wvec_swizzles!(@3 [$($all),*] $a; $($rest)*);
+    };
+
+    (@4 [$($all:ident),*]) => {};
+    (@4 [$($all:ident),*] $a:ident $($rest:ident)*) => {
+        wvec_swizzles!(@4 [$($all),*] $a; $($all)*);
+        wvec_swizzles!(@4 [$($all),*] $($rest)*);
+    };
+    (@4 [$($all:ident),*] $a:ident;) => {};
+    (@4 [$($all:ident),*] $a:ident; $b:ident $($rest:ident)*) => {
+        wvec_swizzles!(@4 [$($all),*] $a $b; $($all)*);
+        wvec_swizzles!(@4 [$($all),*] $a; $($rest)*);
+    };
+    (@4 [$($all:ident),*] $a:ident $b:ident;) => {};
+    (@4 [$($all:ident),*] $a:ident $b:ident; $c:ident $($rest:ident)*) => {
+        paste! {
+            $(
+                fn [< $a $b $c $all >](&self) -> WVec4P0<T> {
+                    WVec4P0::new(self.$a(), self.$b(), self.$c(), self.$all())
+                }
+            )*
+        }
+        wvec_swizzles!(@4 [$($all),*] $a $b; $($rest)*);
+    };
+}
+
+/// Swizzles of the two component vectors. The methods are generated once
+/// here; each padding variant only implements the getters.
+pub trait WSwizzle2<T: WScalars> {
+    fn x(&self) -> T;
+    fn y(&self) -> T;
+
+    wvec_swizzles!(x, y);
+}
+
+/// Swizzles of the three component vectors, see [`WSwizzle2`].
+pub trait WSwizzle3<T: WScalars> {
+    fn x(&self) -> T;
+    fn y(&self) -> T;
+    fn z(&self) -> T;
+
+    wvec_swizzles!(x, y, z);
+}
+
+/// Swizzles of the four component vectors, see [`WSwizzle2`].
+pub trait WSwizzle4<T: WScalars> {
+    fn x(&self) -> T;
+    fn y(&self) -> T;
+    fn z(&self) -> T;
+    fn w(&self) -> T;
+
+    wvec_swizzles!(x, y, z, w);
+}
+
+/// Implements the getters of a swizzle trait.
+macro_rules! wvec_swizzle_impl {
+    ($trait:ident, $vec:ident { $($fields:ident),* }) => {
+        impl<T: WScalars> $trait<T> for $vec<T> {
+            $(
+                fn $fields(&self) -> T {
+                    self.$fields
+                }
+            )*
+        }
+    };
+}
+
+/// Operations that only exist for some vector sizes.
+macro_rules! wvec_extra {
+    (WVec2, $vec:ident) => {
+        wvec_swizzle_impl!(WSwizzle2, $vec { x, y });
+    };
+    (WVec3, $vec:ident) => {
+        wvec_swizzle_impl!(WSwizzle3, $vec { x, y, z });
+
+        impl<T> $vec<T>
+        where
+            T: WScalars + Mul<Output = T> + Sub<Output = T>,
+        {
+            pub fn cross(&self, rhs: &Self) -> Self {
+                Self::new(
+                    self.y * rhs.z - self.z * rhs.y,
+                    self.z * rhs.x - self.x * rhs.z,
+                    self.x * rhs.y - self.y * rhs.x,
+                )
+            }
+        }
+    };
+    (WVec4, $vec:ident) => {
+        wvec_swizzle_impl!(WSwizzle4, $vec { x, y, z, w });
+    };
+}
+
+/// Component-wise arithmetic (also with a scalar on the right) and the usual
+/// vector functions. Every result is built through `new`, so `_pad` stays
+/// zeroed.
+macro_rules! wvec_math {
+    ($struct:ident { $($fields:ident),* }, $vec:ident) => {
+        wvec_binop!($vec { $($fields),* }, Add, add, +);
+        wvec_binop!($vec { $($fields),* }, Sub, sub, -);
+        wvec_binop!($vec { $($fields),* }, Mul, mul, *);
+        wvec_binop!($vec { $($fields),* }, Div, div, /);
+
+        impl<T: WScalars + Neg<Output = T>> Neg for $vec<T> {
+            type Output = Self;
+
+            fn neg(self) -> Self {
+                Self::new($(-self.$fields),*)
+            }
+        }
+
+        impl<T> $vec<T>
+        where
+            T: WScalars + Mul<Output = T> + Add<Output = T>,
+        {
+            pub fn dot(&self, rhs: &Self) -> T {
+                T::default() $(+ self.$fields * rhs.$fields)*
+            }
+        }
+
+        impl<T: WScalars + PartialOrd> $vec<T> {
+            pub fn min(&self, rhs: &Self) -> Self {
+                Self::new($(if rhs.$fields < self.$fields { rhs.$fields } else { self.$fields }),*)
+            }
+
+            pub fn max(&self, rhs: &Self) -> Self {
+                Self::new($(if rhs.$fields > self.$fields { rhs.$fields } else { self.$fields }),*)
+            }
+        }
+
+        impl $vec<f32> {
+            pub fn length(&self) -> f32 {
+                self.dot(self).sqrt()
+            }
+
+            /// Unit vector in the same direction; the zero vector stays zero.
+            pub fn normalize(&self) -> Self {
+                let len = self.length();
+                if len == 0.0 {
+                    *self
+                } else {
+                    *self / len
+                }
+            }
+
+            pub fn lerp(&self, rhs: &Self, t: f32) -> Self {
+                *self + (*rhs - *self) * t
+            }
+        }
+
+        wvec_extra!($struct, $vec);
+    };
+}
+
+/// `vec<scalar>`, leaked once per combination. A `static` inside the generic
+/// `wgsl_type` would be shared by every `T`, hence the map.
+fn wvec_wgsl_type(vec: &'static str, scalar: &'static str) -> &'static str {
+    static TYPES: OnceLock<Mutex<HashMap<(&str, &str), &str>>> = OnceLock::new();
+    let mut types = TYPES.get_or_init(Default::default).lock().unwrap();
+    types
+        .entry((vec, scalar))
+        .or_insert_with(|| Box::leak(format!("{}<{}>", vec, scalar).into_boxed_str()))
+}
+
 macro_rules! wvec_def_struct {
     ($struct:ident { $($fields:ident),* }, $pad:expr, $wgpu_type:expr) => {
         paste! {
@@ -92,9 +624,39 @@ macro_rules! wvec_def_struct {
                 }
             }
 
-            impl<T: WScalars> WTestable for [< $struct P $pad >]<T> {
+            /// Square matrix times column vector
+            impl<T: WScalars, const FORCED_M: usize>
+                Mul<[< $struct P $pad >]<T>>
+                for WMat<T, { wvec_len!($($fields),*) }, { wvec_len!($($fields),*) }, FORCED_M>
+            where
+                T: Mul<Output = T> + Add<Output = T>,
+                [[T; FORCED_M]; wvec_len!($($fields),*)]: Default + Zeroable,
+            {
+                type Output = [< $struct P $pad >]<T>;
+
+                fn mul(self, rhs: [< $struct P $pad >]<T>) -> Self::Output {
+                    let v = [$(rhs.$fields),*];
+                    let mut out = [T::default(); wvec_len!($($fields),*)];
+                    for (col, x) in self.matrix().iter().zip(v) {
+                        for (o, m) in out.iter_mut().zip(col.iter()) {
+                            *o = *o + *m * x;
+                        }
+                    }
+                    let [$($fields),*] = out;
+                    Self::Output::new($($fields),*)
+                }
+            }
+
+            wvec_math!($struct { $($fields),* }, [< $struct P $pad >]);
+
+            impl<T: WScalars + WLayout> WLayout for [< $struct P $pad >]<T> {
+                const ALIGN: usize = wvec_align(wvec_len!($($fields),*), T::SIZE);
+                const SIZE: usize = wvec_len!($($fields),*) * T::SIZE;
+            }
+
+            impl<T: WScalars + WTestable> WTestable for [< $struct P $pad >]<T> {
                 fn wgsl_type() -> WType {
-                    $wgpu_type
+                    WType::Primitive(wvec_wgsl_type($wgpu_type, T::inner_type()))
                 }
             }
 
@@ -150,23 +712,30 @@ wvec_def!(
     WVec2 { x, y },
     wvec2,
     [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14],
-    WType::Primitive("vec2<f32>")
+    "vec2"
 );
 
 wvec_def!(
     WVec3 { x, y, z },
     wvec3,
     [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13],
-    WType::Primitive("vec3<f32>")
+    "vec3"
 );
 
 wvec_def!(
     WVec4 { x, y, z, w },
     wvec4,
     [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
-    WType::Primitive("vec4<f32>")
+    "vec4"
 );
 
+#[cfg(test)]
+type WBoolVec2 = wvec2!(WBool, 0);
+#[cfg(test)]
+type WBoolVec4 = wvec4!(WBool, 0);
+wtest!(WBoolVec2, 256);
+wtest!(WBoolVec4, 256);
+
 #[macro_export]
 macro_rules! wpad {
     ($pad:expr) => {
@@ -176,6 +745,16 @@ macro_rules! wpad {
 
 pub type WMat3x3Affine = WMat<f32, 3, 3, 4>;
 
+/// `N` columns of `vecM`.
+impl<T: WScalars + WLayout, const N: usize, const M: usize, const FORCED_M: usize> WLayout
+    for WMat<T, N, M, FORCED_M>
+where
+    [[T; FORCED_M]; N]: Default + Zeroable,
+{
+    const ALIGN: usize = wvec_align(M, T::SIZE);
+    const SIZE: usize = N * wround_up(wvec_align(M, T::SIZE), M * T::SIZE);
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, Zeroable, PartialEq)]
 pub struct WMat<T, const N: usize, const M: usize, const FORCED_M: usize>([[T; FORCED_M]; N])
@@ -208,7 +787,7 @@ where
                 write!(f, "{:width$}", self.0[i][j], width = SPACING)?;
             }
             if i != N - 1 {
-                write!(f, "\n")?;
+                writeln!(f)?;
             }
         }
         write!(f, " ]")?;
@@ -255,31 +834,31 @@ where
     [[T; FORCED_M]; N]: Default + Zeroable,
 {
     pub fn from_row_major<const K: usize, const D: usize>(data: [[T; K]; D]) -> Self {
-        assert_eq!(D, N);
-        assert_eq!(K, M);
+        assert_eq!(D, M);
+        assert_eq!(K, N);
         let mut m = Self::default();
-        for i in 0..N {
-            for j in 0..M {
-                m.0[i][j] = data[j][i];
+        for (i, col) in m.0.iter_mut().enumerate() {
+            for (j, v) in col.iter_mut().take(M).enumerate() {
+                *v = data[j][i];
             }
         }
         m
     }
 
     pub fn from_col_major<const K: usize, const D: usize>(data: [[T; K]; D]) -> Self {
-        assert_eq!(K, N);
-        assert_eq!(D, M);
+        assert_eq!(D, N);
+        assert_eq!(K, M);
         let mut m = Self::default();
-        for i in 0..N {
-            for j in 0..M {
-                m.0[i][j] = data[i][j];
+        for (i, col) in m.0.iter_mut().enumerate() {
+            for (j, v) in col.iter_mut().take(M).enumerate() {
+                *v = data[i][j];
             }
         }
         m
     }
 
     pub fn set(&mut self, x: usize, y: usize, v: T) {
-        assert!(x < M && y < N);
+        assert!(x < N && y < M);
         self.0[x][y] = v;
     }
 
@@ -296,55 +875,370 @@ where
     }
 }
 
-impl<T: WScalars, const FORCED_M: usize> WMat<T, 3, 3, FORCED_M>
+impl<T: WScalars, const N: usize, const M: usize, const FORCED_M: usize> WMat<T, N, M, FORCED_M>
+where
+    [[T; FORCED_M]; N]: Default + Zeroable,
+{
+    /// Transpose into a matrix with the given column padding, e.g.
+    /// `m.transpose_padded::<4>()` for a `mat2x3` going to a `mat3x2`.
+    pub fn transpose_padded<const FORCED_N: usize>(&self) -> WMat<T, M, N, FORCED_N>
+    where
+        [[T; FORCED_N]; M]: Default + Zeroable,
+    {
+        let mut t = WMat::<T, M, N, FORCED_N>::default();
+        for (i, col) in self.0.iter().enumerate() {
+            for (j, v) in col.iter().take(M).enumerate() {
+                t.0[j][i] = *v;
+            }
+        }
+        t
+    }
+}
+
+impl<T: WScalars, const N: usize, const FORCED_M: usize> WMat<T, N, N, FORCED_M>
+where
+    T: Add<Output = T> + From<u8>,
+    [[T; FORCED_M]; N]: Default + Zeroable,
+{
+    pub fn identity() -> Self {
+        let mut m = Self::default();
+        for (i, col) in m.0.iter_mut().enumerate() {
+            col[i] = T::from(1);
+        }
+        m
+    }
+
+    pub fn transpose(&self) -> Self {
+        self.transpose_padded::<FORCED_M>()
+    }
+
+    pub fn trace(&self) -> T {
+        self.0
+            .iter()
+            .enumerate()
+            .fold(T::from(0), |acc, (i, col)| acc + col[i])
+    }
+}
+
+/// LU based operations. These divide, so they are only meaningful for float
+/// element types.
+impl<T: WScalars, const N: usize, const FORCED_M: usize> WMat<T, N, N, FORCED_M>
 where
-    T: Default
-        + Mul<Output = T>
+    T: Mul<Output = T>
         + Sub<Output = T>
         + Neg<Output = T>
         + Add<Output = T>
         + Div<Output = T>
         + From<u8>
-        + Copy
-        + PartialEq,
-    [[T; FORCED_M]; 3]: Default + Zeroable,
+        + PartialOrd,
+    [[T; FORCED_M]; N]: Default + Zeroable,
 {
-    pub fn try_inverse(&self) -> Result<Self, Box<dyn std::error::Error>> {
-        let m = &self.0;
-        let a = m[0][0];
-        let b = m[0][1];
-        let c = m[0][2];
-        let d = m[1][0];
-        let e = m[1][1];
-        let f = m[1][2];
-        let g = m[2][0];
-        let h = m[2][1];
-        let i = m[2][2];
-
-        let t_a = e * i - f * h;
-        let t_b = -(d * i - f * g);
-        let t_c = d * h - e * g;
-        let t_d = -(b * i - c * h);
-        let t_e = a * i - c * g;
-        let t_f = -(a * h - b * g);
-        let t_g = b * f - c * e;
-        let t_h = -(a * f - c * d);
-        let t_i = a * e - b * d;
-
-        let det = a * t_a + b * t_b + c * t_c;
-        if det == T::from(0) {
-            return Err("Determinant is zero. There exists no inverse for this matrix!".into());
+    /// Doolittle LU decomposition with partial pivoting, stored row major as
+    /// `L - I + U`. Also returns the row permutation and whether it is odd.
+    /// `None` if the matrix is singular.
+    fn lu(&self) -> Option<([[T; N]; N], [usize; N], bool)> {
+        let zero = T::from(0);
+        let abs = |v: T| if v < zero { -v } else { v };
+        let mut a = [[zero; N]; N];
+        for (c, col) in self.0.iter().enumerate() {
+            for (row, v) in a.iter_mut().zip(col.iter()) {
+                row[c] = *v;
+            }
+        }
+        let mut perm: [usize; N] = core::array::from_fn(|i| i);
+        let mut odd = false;
+        for k in 0..N {
+            let mut p = k;
+            for i in k + 1..N {
+                if abs(a[i][k]) > abs(a[p][k]) {
+                    p = i;
+                }
+            }
+            if a[p][k] == zero {
+                return None;
+            }
+            if p != k {
+                a.swap(p, k);
+                perm.swap(p, k);
+                odd = !odd;
+            }
+            let pivot = a[k];
+            for row in a.iter_mut().skip(k + 1) {
+                let f = row[k] / pivot[k];
+                row[k] = f;
+                for j in k + 1..N {
+                    row[j] = row[j] - f * pivot[j];
+                }
+            }
         }
-        let inv_det = T::from(1) / det;
+        Some((a, perm, odd))
+    }
 
-        Ok(Self::from_row_major([
-            [t_a * inv_det, t_b * inv_det, t_c * inv_det],
-            [t_d * inv_det, t_e * inv_det, t_f * inv_det],
-            [t_g * inv_det, t_h * inv_det, t_i * inv_det],
-        ]))
+    pub fn determinant(&self) -> T {
+        match self.lu() {
+            None => T::from(0),
+            Some((a, _, odd)) => {
+                let det = (0..N).fold(T::from(1), |acc, i| acc * a[i][i]);
+                if odd {
+                    -det
+                } else {
+                    det
+                }
+            }
+        }
+    }
+
+    pub fn try_inverse(&self) -> Result<Self, WError> {
+        let (a, perm, _) = self.lu().ok_or(WError::SingularMatrix)?;
+        let mut inv = Self::default();
+        // Column c of the inverse solves LU x = P e_c
+        for (c, col) in inv.0.iter_mut().enumerate() {
+            let mut x: [T; N] = core::array::from_fn(|i| T::from((perm[i] == c) as u8));
+            for i in 0..N {
+                for j in 0..i {
+                    x[i] = x[i] - a[i][j] * x[j];
+                }
+            }
+            for i in (0..N).rev() {
+                for j in i + 1..N {
+                    x[i] = x[i] - a[i][j] * x[j];
+                }
+                x[i] = x[i] / a[i][i];
+            }
+            col[..N].copy_from_slice(&x);
+        }
+        Ok(inv)
     }
 
     pub fn inverse(&self) -> Self {
         self.try_inverse().unwrap()
     }
 }
+
+/// `self * rhs` with `self` having `M` rows and `N` columns and `rhs` having
+/// `N` rows and `K` columns. The result keeps the row padding of `self`.
+impl<
+        T: WScalars,
+        const N: usize,
+        const M: usize,
+        const FORCED_M: usize,
+        const K: usize,
+        const FORCED_N: usize,
+    > Mul<WMat<T, K, N, FORCED_N>> for WMat<T, N, M, FORCED_M>
+where
+    T: Mul<Output = T> + Add<Output = T>,
+    [[T; FORCED_M]; N]: Default + Zeroable,
+    [[T; FORCED_N]; K]: Default + Zeroable,
+    [[T; FORCED_M]; K]: Default + Zeroable,
+{
+    type Output = WMat<T, K, M, FORCED_M>;
+
+    fn mul(self, rhs: WMat<T, K, N, FORCED_N>) -> Self::Output {
+        let mut out = WMat::<T, K, M, FORCED_M>::default();
+        for (out_col, rhs_col) in out.0.iter_mut().zip(rhs.0.iter()) {
+            for (i, col) in self.0.iter().enumerate() {
+                for (o, v) in out_col.iter_mut().zip(col.iter()).take(M) {
+                    *o = *o + *v * rhs_col[i];
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Forward (source to destination) affine builders, laid out like OpenCV's
+/// 2x3 matrices with `[0, 0, 1]` as the last row. `ImageTransform` wants the
+/// inverse, see `ImageTransform::from_forward`.
+impl WMat<f32, 3, 3, 4> {
+    pub fn translate(tx: f32, ty: f32) -> Self {
+        Self::from_row_major([[1.0, 0.0, tx], [0.0, 1.0, ty], [0.0, 0.0, 1.0]])
+    }
+
+    pub fn scale(sx: f32, sy: f32) -> Self {
+        Self::from_row_major([[sx, 0.0, 0.0], [0.0, sy, 0.0], [0.0, 0.0, 1.0]])
+    }
+
+    pub fn shear(shx: f32, shy: f32) -> Self {
+        Self::from_row_major([[1.0, shx, 0.0], [shy, 1.0, 0.0], [0.0, 0.0, 1.0]])
+    }
+
+    /// Rotation by `angle` degrees about `center`; positive angles are
+    /// counter-clockwise on screen (y pointing down).
+    pub fn rotate(center: PointF, angle: f32) -> Self {
+        Self::get_rotation_matrix_2d(center, angle, 1.0)
+    }
+
+    /// Equivalent of OpenCV's `getRotationMatrix2D`.
+    pub fn get_rotation_matrix_2d(center: PointF, angle: f32, scale: f32) -> Self {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let alpha = scale * cos;
+        let beta = scale * sin;
+        Self::from_row_major([
+            [alpha, beta, (1.0 - alpha) * center.x - beta * center.y],
+            [-beta, alpha, beta * center.x + (1.0 - alpha) * center.y],
+            [0.0, 0.0, 1.0],
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Pix;
+
+    fn assert_mat_close<const N: usize, const M: usize, const F: usize>(
+        a: &WMat<f32, N, M, F>,
+        b: &WMat<f32, N, M, F>,
+    ) where
+        [[f32; F]; N]: Default + Zeroable,
+    {
+        for i in 0..N {
+            for j in 0..M {
+                assert!((a.get(i, j) - b.get(i, j)).abs() < 1e-4, "\n{}\n{}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn mat_mul_composes_transforms() {
+        let t = WMat3x3Affine::translate(3.0, -2.0);
+        let s = WMat3x3Affine::scale(2.0, 0.5);
+        // Scale first, then translate
+        let m = t * s;
+        let expected =
+            WMat3x3Affine::from_row_major([[2.0, 0.0, 3.0], [0.0, 0.5, -2.0], [0.0, 0.0, 1.0]]);
+        assert_mat_close(&m, &expected);
+        assert_mat_close(&(m * WMat3x3Affine::identity()), &m);
+
+        let p = m * <wvec3!(f32, 4)>::new(4.0, 4.0, 1.0);
+        assert_eq!((p.x, p.y, p.z), (11.0, 0.0, 1.0));
+
+        // mat2x3 (2 columns, 3 rows) * mat2x2 -> mat2x3
+        let a = WMat::<f32, 2, 3, 4>::from_col_major([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]);
+        let b = WMat::<f32, 2, 2, 2>::from_col_major([[1.0, 0.0], [1.0, 1.0]]);
+        let expected = WMat::<f32, 2, 3, 4>::from_col_major([[1.0, 2.0, 3.0], [5.0, 7.0, 9.0]]);
+        assert_mat_close(&(a * b), &expected);
+        let at: WMat<f32, 3, 2, 2> = a.transpose_padded();
+        assert_eq!(at.get(2, 1), 6.0);
+    }
+
+    #[test]
+    fn lu_inverse_and_determinant() {
+        let m = WMat::<f32, 4, 4, 4>::from_row_major([
+            [0.0, 2.0, 1.0, 4.0],
+            [1.0, 1.0, 0.0, 2.0],
+            [3.0, 0.0, 2.0, 1.0],
+            [2.0, 1.0, 1.0, 0.0],
+        ]);
+        assert!((m.determinant() - m.transpose().determinant()).abs() < 1e-4);
+        assert_eq!(m.trace(), 3.0);
+        assert_mat_close(&(m * m.inverse()), &WMat::identity());
+
+        let rot = WMat3x3Affine::get_rotation_matrix_2d(PointF::new(5.0, 7.0), 30.0, 2.0);
+        assert!((rot.determinant() - 4.0).abs() < 1e-4);
+        assert_mat_close(&(rot.inverse() * rot), &WMat::identity());
+
+        let singular =
+            WMat3x3Affine::from_row_major([[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 1.0, 0.0]]);
+        assert_eq!(singular.determinant(), 0.0);
+        assert!(matches!(
+            singular.try_inverse(),
+            Err(WError::SingularMatrix)
+        ));
+    }
+
+    #[test]
+    fn vec_math() {
+        let a = <wvec3!(f32, 4)>::new(1.0, 2.0, 3.0);
+        let b = <wvec3!(f32, 4)>::new(4.0, -5.0, 6.0);
+        assert_eq!(a + b, <wvec3!(f32, 4)>::new(5.0, -3.0, 9.0));
+        assert_eq!(-(a - b) * 2.0, <wvec3!(f32, 4)>::new(6.0, -14.0, 6.0));
+        assert_eq!(a.dot(&b), 12.0);
+        // Right handed, and perpendicular to both inputs
+        let c = a.cross(&b);
+        assert_eq!(c, <wvec3!(f32, 4)>::new(27.0, 6.0, -13.0));
+        assert_eq!((c.dot(&a), c.dot(&b)), (0.0, 0.0));
+        assert_eq!(
+            a.min(&b).max(&<wvec3!(f32, 4)>::new(0.0, 0.0, 0.0)),
+            <wvec3!(f32, 4)>::new(1.0, 0.0, 3.0)
+        );
+        assert_eq!(a.lerp(&b, 0.5), <wvec3!(f32, 4)>::new(2.5, -1.5, 4.5));
+
+        let v = <wvec2!(f32, 8)>::new(3.0, 4.0);
+        assert_eq!(v.length(), 5.0);
+        assert!((v.normalize().length() - 1.0).abs() < 1e-6);
+        assert_eq!(v.normalize()._pad, [0; 8]);
+        assert_eq!(v.yx(), <wvec2!(f32, 0)>::new(4.0, 3.0));
+
+        let w = <wvec4!(u32, 0)>::new(1, 2, 3, 4);
+        assert_eq!(w.wzyx(), <wvec4!(u32, 0)>::new(4, 3, 2, 1));
+        assert_eq!((w / 2).yzw(), <wvec3!(u32, 0)>::new(1, 1, 2));
+        assert_eq!(w.xw(), <wvec2!(u32, 0)>::new(1, 4));
+        assert_eq!(w.xyw(), <wvec3!(u32, 0)>::new(1, 2, 4));
+        assert_eq!(w.wwxz(), <wvec4!(u32, 0)>::new(4, 4, 1, 3));
+        assert_eq!(a.xxx(), <wvec3!(f32, 0)>::new(1.0, 1.0, 1.0));
+        assert_eq!(v.yyxy(), <wvec4!(f32, 0)>::new(4.0, 4.0, 3.0, 4.0));
+    }
+
+    #[test]
+    fn f16_layout_and_packing() {
+        assert_eq!((<wvec3!(f16, 2)>::ALIGN, <wvec3!(f16, 2)>::SIZE), (8, 6));
+        assert_eq!(WMat::<f16, 2, 2, 2>::SIZE, 8);
+        let v = <wvec2!(f16, 0)>::new(f16::from_f32(1.5), f16::from_f32(-2.0));
+        assert_eq!((v + v).y, f16::from_f32(-4.0));
+
+        assert_eq!(pack4x8unorm([0.0, 1.0, 0.5, 2.0]), 0xff_80_ff_00);
+        assert_eq!(unpack4x8unorm(0xff_80_ff_00)[1], 1.0);
+        assert_eq!(pack4x8snorm([-1.0, 1.0, 0.0, -2.0]), 0x81_00_7f_81);
+        assert_eq!(unpack4x8snorm(0x80)[0], -1.0);
+        assert_eq!(
+            unpack2x16unorm(pack2x16unorm([0.25, 1.0])),
+            [16384.0 / 65535.0, 1.0]
+        );
+        assert_eq!(unpack2x16snorm(pack2x16snorm([-0.5, 1.0]))[1], 1.0);
+        assert_eq!(unpack2x16float(pack2x16float([1.5, -0.25])), [1.5, -0.25]);
+    }
+
+    #[test]
+    fn vec_wgsl_types_are_cached() {
+        let name = <wvec3!(f32, 4)>::inner_type();
+        assert_eq!(name, "vec3<f32>");
+        assert!(std::ptr::eq(name, <wvec3!(f32, 0)>::inner_type()));
+        assert_eq!(<wvec2!(u32, 0)>::inner_type(), "vec2<u32>");
+    }
+
+    #[test]
+    fn wbool_conversions() {
+        assert_eq!(WBool::from(true), WBool::TRUE);
+        assert!(bool::from(WBool(7)));
+        assert!(!WBool::default().get());
+        let v = <wvec2!(WBool, 0)>::new(true.into(), false.into());
+        assert_eq!(bytemuck::cast::<_, [u32; 2]>(v), [1, 0]);
+    }
+
+    #[test]
+    fn host_dev_byte_conversions() {
+        let m = WMat3x3Affine::translate(1.0, 2.0);
+        assert_eq!(WMat3x3Affine::from_bytes_new(m.bytes()).unwrap(), m);
+        // Unaligned input is fine, a wrong length is not
+        let mut raw = vec![0u8];
+        raw.extend_from_slice(m.bytes());
+        assert_eq!(WMat3x3Affine::from_bytes_new(&raw[1..]).unwrap(), m);
+        assert!(matches!(
+            u32::from_bytes_new(&raw[..3]),
+            Err(WError::ByteLength {
+                expected: 4,
+                got: 3
+            })
+        ));
+
+        let mut im = Image::new(crate::image::Size::new(2, 2));
+        im.data[3] = Pix::new(1, 2, 3, 4);
+        let mut back = Image::<Pix>::new(im.size);
+        back.from_bytes(im.bytes()).unwrap();
+        assert_eq!(back.data, im.data);
+        assert_eq!(Image::<Pix>::from_bytes_new(im.bytes()).unwrap().size.x, 4);
+        let mut short = [0u32; 3];
+        assert!(short.as_mut_slice().from_bytes(im.bytes()).is_err());
+    }
+}